@@ -0,0 +1,80 @@
+// the file is looked for with every supported extension, like config/local.toml
+pub const DEFAULT_CONFIG: &str = "config/local";
+
+pub const USAGE: &str = "usage: holysee [--config <file>] [check-config]
+
+  --config <file>  configuration file, config/local.toml by default
+  check-config     validate the configuration and report every problem
+
+Environment variables override the file: HOLYSEE_TELEGRAM__TOKEN sets telegram.token.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Run,
+    CheckConfig,
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub config: String,
+    pub command: Command,
+}
+
+/// Parses the command line arguments, without the program name.
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut parsed = Args {
+        config: String::from(DEFAULT_CONFIG),
+        command: Command::Run,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" | "-c" => {
+                parsed.config = match args.next() {
+                    Some(config) => config,
+                    None => return Err(format!("{} needs a file name", arg)),
+                }
+            }
+            "check-config" => parsed.command = Command::CheckConfig,
+            "--help" | "-h" | "help" => parsed.command = Command::Help,
+            _ if arg.starts_with("--config=") => {
+                parsed.config = String::from(&arg["--config=".len()..]);
+            }
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Args, Command, DEFAULT_CONFIG};
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse(args.iter().map(|a| String::from(*a)))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            args(&[]).unwrap(),
+            Args {
+                config: String::from(DEFAULT_CONFIG),
+                command: Command::Run,
+            }
+        );
+        assert_eq!(
+            args(&["--config", "/etc/holysee.toml", "check-config"]).unwrap(),
+            Args {
+                config: String::from("/etc/holysee.toml"),
+                command: Command::CheckConfig,
+            }
+        );
+        assert_eq!(
+            args(&["check-config", "--config=holysee.toml"]).unwrap().config,
+            "holysee.toml"
+        );
+        assert!(args(&["--config"]).is_err());
+        assert!(args(&["--verbose"]).is_err());
+    }
+}
@@ -1,55 +1,80 @@
 extern crate regex;
 
-use chan::Sender;
-use message::Message;
+use message::{Message, MessageType};
+use settings::Settings;
+use transport::TransportRegistry;
 
 pub trait Command {
-    fn execute(&mut self, &mut Message, &Sender<Message>, &Sender<Message>);
+    fn execute(&mut self, &mut Message, &TransportRegistry);
     fn get_usage(&self) -> String;
     fn get_name(&self) -> String;
     fn matches_message_text(&self, message: &Message) -> bool;
     fn stop_processing(&self, message: &Message) -> bool;
+    /// Applies the settings that can change while running, on SIGHUP.
+    fn reload(&mut self, settings: &Settings);
 }
 
+/// Runs the enabled commands on every message. All of them are registered, so the enabled set
+/// can be changed by reload().
 pub struct CommandDispatcher<'a> {
     commands: Vec<&'a mut Command>,
-    enabled_commands: &'a [String],
+    enabled_commands: Vec<String>,
+}
+
+fn is_enabled(enabled_commands: &[String], command: &str) -> bool {
+    enabled_commands.into_iter().any(|x| x == command)
 }
 
 impl<'a> CommandDispatcher<'a> {
-    pub fn new(enabled_commands: &'a [String]) -> CommandDispatcher<'a> {
+    pub fn new(enabled_commands: &[String]) -> CommandDispatcher<'a> {
         CommandDispatcher {
             commands: vec![],
-            enabled_commands,
+            enabled_commands: enabled_commands.to_vec(),
         }
     }
 
     pub fn is_command_enabled(&self, command: &str) -> bool {
-        self.enabled_commands.into_iter().any(|x| x == command)
+        is_enabled(&self.enabled_commands, command)
     }
 
     pub fn register(&mut self, cmd: &'a mut Command) {
         if self.is_command_enabled(&cmd.get_name()) {
             info!("Registering new command {}", cmd.get_name());
-            self.commands.push(cmd);
         } else {
-            warn!(
-                "Command {} is disabled in settings, skipping registration",
-                cmd.get_name()
-            );
+            warn!("Command {} is disabled in settings", cmd.get_name());
         }
+        self.commands.push(cmd);
     }
 
-    pub fn execute(
-        &mut self,
-        msg: &mut Message,
-        irc_sender: &Sender<Message>,
-        tg_sender: &Sender<Message>,
-    ) {
+    /// Switches to the commands enabled in `settings` and reloads every command.
+    pub fn reload(&mut self, settings: &Settings) {
         for command in self.commands.as_mut_slice() {
+            let name = command.get_name();
+            let enabled = is_enabled(&settings.commands.enabled, &name);
+            if enabled != is_enabled(&self.enabled_commands, &name) {
+                info!(
+                    "Command {} {}",
+                    name,
+                    if enabled { "enabled" } else { "disabled" }
+                );
+            }
+            command.reload(settings);
+        }
+        self.enabled_commands = settings.commands.enabled.clone();
+    }
+
+    pub fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
+        for command in self.commands.as_mut_slice() {
+            if !is_enabled(&self.enabled_commands, &command.get_name()) {
+                continue;
+            }
+            // commands only act on what people write, edits and announcements are just relayed
+            if msg.message_type != MessageType::Text && command.get_name() != "relay" {
+                continue;
+            }
             if command.matches_message_text(msg) {
                 info!("Executing command {}", command.get_name());
-                command.execute(msg, irc_sender, tg_sender);
+                command.execute(msg, transports);
                 if command.stop_processing(msg) {
                     debug!("Command {} stop processing", command.get_name());
                     break;
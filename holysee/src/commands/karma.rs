@@ -1,25 +1,26 @@
 extern crate regex;
 extern crate serde_json;
 
-use chan::Sender;
 use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::error::Error;
 
 use self::regex::{Regex, Captures};
 
-use message::{Message, TransportType, DestinationType};
+use message::{Message, DestinationType};
 use commands::command_dispatcher::Command;
+use settings::Settings;
+use transport::TransportRegistry;
 
 #[derive(Debug)]
-pub struct KarmaCommand<'a> {
+pub struct KarmaCommand {
     karma: HashMap<String, i64>,
-    command_prefix: &'a str,
-    data_dir: &'a str,
+    command_prefix: String,
+    data_dir: String,
 }
 
-impl<'a> KarmaCommand<'a> {
-    pub fn new(command_prefix: &'a str, data_dir: &'a str) -> KarmaCommand<'a> {
+impl KarmaCommand {
+    pub fn new(command_prefix: &str, data_dir: &str) -> KarmaCommand {
         KarmaCommand {
             karma: match KarmaCommand::read_database(data_dir, "karma") {
                 Ok(v) => v,
@@ -28,8 +29,8 @@ impl<'a> KarmaCommand<'a> {
                     HashMap::new()
                 }
             },
-            command_prefix: command_prefix,
-            data_dir: data_dir,
+            command_prefix: String::from(command_prefix),
+            data_dir: String::from(data_dir),
         }
     }
 
@@ -110,44 +111,25 @@ impl<'a> KarmaCommand<'a> {
     }
 }
 
-impl<'a> Command for KarmaCommand<'a> {
-    fn execute(
-        &mut self,
-        msg: &mut Message,
-        to_irc: &Sender<Message>,
-        to_telegram: &Sender<Message>,
-    ) {
-        let karma_irc = self.handle(&msg.text);
-        let karma_telegram = karma_irc.clone();
+impl Command for KarmaCommand {
+    fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
+        let karma = self.handle(&msg.text);
 
         let destination = match msg.to {
             DestinationType::Channel(ref c) => DestinationType::Channel(c.clone()),
             DestinationType::User(_) => DestinationType::User(msg.from.clone()),
             DestinationType::Unknown => panic!("Serious bug in karma command handler"),
         };
-        let destination_irc: DestinationType = DestinationType::klone(&destination);
-        let destination_telegram: DestinationType = DestinationType::klone(&destination);
-
-        match msg.from_transport {
-            TransportType::IRC => {
-                to_irc.send(Message::new(
-                    TransportType::Telegram,
-                    karma_irc,
-                    String::from("KarmaCommand"),
-                    destination_irc,
-                    true,
-                ));
-            }
-            TransportType::Telegram => {
-                to_telegram.send(Message::new(
-                    TransportType::IRC,
-                    karma_telegram,
-                    String::from("KarmaCommand"),
-                    destination_telegram,
-                    true,
-                ));
-            }
-        }
+        transports.send(
+            &msg.from_transport,
+            Message::new(
+                msg.from_transport,
+                karma,
+                String::from("KarmaCommand"),
+                destination,
+                true,
+            ),
+        );
     }
 
     fn get_usage(&self) -> String {
@@ -181,6 +163,10 @@ to decrement it.",
     fn stop_processing(&self, _: &Message) -> bool {
         true
     }
+
+    fn reload(&mut self, settings: &Settings) {
+        self.command_prefix = settings.command_prefix.clone();
+    }
 }
 
 #[cfg(test)]
@@ -189,7 +175,8 @@ mod tests {
 
     use self::tempdir::TempDir;
 
-    use super::{Command, KarmaCommand, Message, TransportType, DestinationType};
+    use super::{Command, KarmaCommand, Message, DestinationType};
+    use message::{TransportType, MessageType};
 
     #[test]
     fn test_read_database() {
@@ -221,6 +208,7 @@ mod tests {
             from: String::from("auser"),
             to: DestinationType::User(String::from("auser")),
             is_from_command: false,
+            message_type: MessageType::Text,
         };
 
         let success = [
@@ -5,23 +5,24 @@ extern crate chrono;
 use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::error::Error;
-use chan::Sender;
 
 use self::regex::Regex;
 use self::chrono::{Local, NaiveDateTime};
 
-use message::{Message, TransportType, DestinationType};
+use message::{Message, DestinationType};
 use commands::command_dispatcher::Command;
+use settings::Settings;
+use transport::TransportRegistry;
 
 #[derive(Debug)]
-pub struct LastSeenCommand<'a> {
+pub struct LastSeenCommand {
     last_seen: HashMap<String, i64>,
-    command_prefix: &'a str,
-    data_dir: &'a str,
+    command_prefix: String,
+    data_dir: String,
 }
 
-impl<'a> LastSeenCommand<'a> {
-    pub fn new(command_prefix: &'a str, data_dir: &'a str) -> LastSeenCommand<'a> {
+impl LastSeenCommand {
+    pub fn new(command_prefix: &str, data_dir: &str) -> LastSeenCommand {
         LastSeenCommand {
             last_seen: match LastSeenCommand::read_database(data_dir, "last_seen") {
                 Ok(v) => v,
@@ -30,8 +31,8 @@ impl<'a> LastSeenCommand<'a> {
                     HashMap::new()
                 }
             },
-            command_prefix,
-            data_dir: data_dir,
+            command_prefix: String::from(command_prefix),
+            data_dir: String::from(data_dir),
         }
     }
 
@@ -100,44 +101,26 @@ impl<'a> LastSeenCommand<'a> {
     }
 }
 
-impl<'a> Command for LastSeenCommand<'a> {
-    fn execute(
-        &mut self,
-        msg: &mut Message,
-        to_irc: &Sender<Message>,
-        to_telegram: &Sender<Message>,
-    ) {
-        let last_seen_irc = self.handle(&msg.text, &msg.from);
-        if last_seen_irc != "" {
-            let last_seen_telegram = last_seen_irc.clone();
+impl Command for LastSeenCommand {
+    fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
+        let last_seen = self.handle(&msg.text, &msg.from);
+        if last_seen != "" {
             let destination = match msg.to {
                 DestinationType::Channel(ref c) => DestinationType::Channel(c.clone()),
                 DestinationType::User(_) => DestinationType::User(msg.from.clone()),
                 DestinationType::Unknown => panic!("Serious bug in last_seen command handler"),
             };
-            let destination_irc: DestinationType = DestinationType::klone(&destination);
-            let destination_telegram: DestinationType = DestinationType::klone(&destination);
             // SEND MESSAGES
-            match msg.from_transport {
-                TransportType::IRC => {
-                    to_irc.send(Message::new(
-                        TransportType::Telegram,
-                        last_seen_irc,
-                        String::from("LastSeenCommand"),
-                        destination_irc,
-                        true,
-                    ));
-                }
-                TransportType::Telegram => {
-                    to_telegram.send(Message::new(
-                        TransportType::IRC,
-                        last_seen_telegram,
-                        String::from("LastSeenCommand"),
-                        destination_telegram,
-                        true,
-                    ));
-                }
-            }
+            transports.send(
+                &msg.from_transport,
+                Message::new(
+                    msg.from_transport,
+                    last_seen,
+                    String::from("LastSeenCommand"),
+                    destination,
+                    true,
+                ),
+            );
         }
     }
 
@@ -164,6 +147,10 @@ command. Note that all timestamps are relative to the server's timezone, usually
         let pattern = format!("{}seen", &self.command_prefix);
         msg.text.contains(&pattern)
     }
+
+    fn reload(&mut self, settings: &Settings) {
+        self.command_prefix = settings.command_prefix.clone();
+    }
 }
 
 #[cfg(test)]
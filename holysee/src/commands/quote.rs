@@ -3,7 +3,6 @@ extern crate serde_json;
 extern crate chrono;
 extern crate rand;
 
-use chan::Sender;
 use std::fs::OpenOptions;
 use std::error::Error;
 use std::boxed::Box;
@@ -13,8 +12,10 @@ use self::regex::Regex;
 use self::chrono::Local;
 use self::rand::distributions::{IndependentSample, Range};
 
-use message::{Message, TransportType, DestinationType};
+use message::{Message, DestinationType};
 use commands::command_dispatcher::Command;
+use settings::Settings;
+use transport::TransportRegistry;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Quote {
@@ -34,14 +35,14 @@ impl Quote {
 }
 
 #[derive(Debug)]
-pub struct QuoteCommand<'a> {
+pub struct QuoteCommand {
     quotes: Vec<Quote>,
-    command_prefix: &'a str,
-    data_dir: &'a str,
+    command_prefix: String,
+    data_dir: String,
 }
 
-impl<'a> QuoteCommand<'a> {
-    pub fn new(command_prefix: &'a str, data_dir: &'a str) -> QuoteCommand<'a> {
+impl QuoteCommand {
+    pub fn new(command_prefix: &str, data_dir: &str) -> QuoteCommand {
         QuoteCommand {
             quotes: match QuoteCommand::read_database(data_dir, "quote") {
                 Ok(v) => v,
@@ -50,8 +51,8 @@ impl<'a> QuoteCommand<'a> {
                     vec![]
                 }
             },
-            command_prefix,
-            data_dir: data_dir,
+            command_prefix: String::from(command_prefix),
+            data_dir: String::from(data_dir),
         }
     }
 
@@ -193,45 +194,26 @@ impl<'a> QuoteCommand<'a> {
     }
 }
 
-impl<'a> Command for QuoteCommand<'a> {
-    fn execute(
-        &mut self,
-        msg: &mut Message,
-        to_irc: &Sender<Message>,
-        to_telegram: &Sender<Message>,
-    ) {
-        let quote_irc = self.handle(&msg.text, &msg.from);
-        let quote_telegram = quote_irc.clone();
+impl Command for QuoteCommand {
+    fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
+        let quote = self.handle(&msg.text, &msg.from);
 
         let destination = match msg.to {
             DestinationType::Channel(ref c) => DestinationType::Channel(c.clone()),
             DestinationType::User(_) => DestinationType::User(msg.from.clone()),
             DestinationType::Unknown => panic!("Serious bug in quote command handler"),
         };
-        let destination_irc: DestinationType = DestinationType::klone(&destination);
-        let destination_telegram: DestinationType = DestinationType::klone(&destination);
-
         // SEND MESSAGES
-        match msg.from_transport {
-            TransportType::IRC => {
-                to_irc.send(Message::new(
-                    TransportType::Telegram,
-                    quote_irc,
-                    String::from("QuoteCommand"),
-                    destination_irc,
-                    true,
-                ));
-            }
-            TransportType::Telegram => {
-                to_telegram.send(Message::new(
-                    TransportType::IRC,
-                    quote_telegram,
-                    String::from("QuoteCommand"),
-                    destination_telegram,
-                    true,
-                ));
-            }
-        }
+        transports.send(
+            &msg.from_transport,
+            Message::new(
+                msg.from_transport,
+                quote,
+                String::from("QuoteCommand"),
+                destination,
+                true,
+            ),
+        );
     }
 
     fn get_usage(&self) -> String {
@@ -263,6 +245,10 @@ to get a specific quote run\
     fn stop_processing(&self, _: &Message) -> bool {
         true
     }
+
+    fn reload(&mut self, settings: &Settings) {
+        self.command_prefix = settings.command_prefix.clone();
+    }
 }
 
 #[cfg(test)]
@@ -271,7 +257,8 @@ mod tests {
 
     use self::tempdir::TempDir;
 
-    use super::{Command, QuoteCommand, Message, TransportType, DestinationType};
+    use super::{Command, QuoteCommand, Message, DestinationType};
+    use message::{TransportType, MessageType};
 
     #[test]
     fn test_read_database() {
@@ -303,6 +290,7 @@ mod tests {
             from: String::from("auser"),
             to: DestinationType::User(String::from("auser")),
             is_from_command: false,
+            message_type: MessageType::Text,
         };
 
         let success = [
@@ -1,67 +1,100 @@
 extern crate regex;
 
+use std::collections::HashMap;
+
 use message::{Message, TransportType, DestinationType};
-use settings::NickEntry;
-use chan::Sender;
+use settings::{NickEntry, Settings};
+use routing::{RoutingTable, Endpoint};
 
 use self::regex::Regex;
 use commands::command_dispatcher::Command;
+use transport::TransportRegistry;
 
 #[derive(Debug)]
-pub struct RelayMessageCommand<'a> {
-    irc_allow_receive: &'a bool,
-    telegram_allow_receive: &'a bool,
-    command_prefix: &'a str,
-    nicknames: &'a [NickEntry],
+pub struct RelayMessageCommand {
+    allow_receive: HashMap<TransportType, bool>,
+    command_prefix: String,
+    nicknames: Vec<NickEntry>,
+    routing: RoutingTable,
 }
 
-impl<'a> RelayMessageCommand<'a> {
+impl RelayMessageCommand {
     pub fn new(
-        irc_allow_receive: &'a bool,
-        telegram_allow_receive: &'a bool,
-        command_prefix: &'a str,
-        nicknames: &'a [NickEntry],
-    ) -> RelayMessageCommand<'a> {
+        allow_receive: HashMap<TransportType, bool>,
+        command_prefix: &str,
+        nicknames: &[NickEntry],
+        routing: RoutingTable,
+    ) -> RelayMessageCommand {
         RelayMessageCommand {
-            irc_allow_receive,
-            telegram_allow_receive,
-            command_prefix,
-            nicknames,
+            allow_receive,
+            command_prefix: String::from(command_prefix),
+            nicknames: nicknames.to_vec(),
+            routing,
         }
     }
-}
 
-impl<'a> Command for RelayMessageCommand<'a> {
-    fn execute(
-        &mut self,
-        msg: &mut Message,
-        irc_sender: &Sender<Message>,
-        telegram_sender: &Sender<Message>,
-    ) {
-        let destination_irc: DestinationType = DestinationType::klone(&msg.to);
-        let destination_telegram: DestinationType = DestinationType::klone(&msg.to);
-        msg.convert_nicknames(self.nicknames);
-        match msg.from_transport {
-            TransportType::IRC => {
-                debug!("Sending message to Telegram chan");
-                telegram_sender.send(Message::new(
-                    TransportType::IRC,
-                    msg.strip_command(self.command_prefix),
-                    msg.from.clone(),
-                    destination_telegram,
-                    msg.is_from_command,
-                ));
+    // private messages stay with the bot, bridges only link channels
+    fn source(message: &Message) -> Option<Endpoint> {
+        match message.to {
+            DestinationType::Channel(ref c) => Some(Endpoint::new(message.from_transport, c)),
+            _ => None,
+        }
+    }
+
+    fn relays_to(&self, message: &Message, destination: &TransportType) -> bool {
+        if message.is_from_command || *self.allow_receive.get(destination).unwrap_or(&false) {
+            return true;
+        }
+        let re = Regex::new(
+            format!(r"^({})(irc|tg|matrix|xmpp)\s+(.*)$", self.command_prefix).as_ref(),
+        ).unwrap();
+        re.is_match(&message.text)
+    }
+
+    /// The copies of `msg` sent along the routes of its channel, with their transport and the
+    /// nicknames of that transport. Endpoints on the same transport, like two IRC channels, are
+    /// routed like any other.
+    fn relayed(&self, msg: &Message) -> Vec<(TransportType, Message)> {
+        let source = match RelayMessageCommand::source(msg) {
+            Some(source) => source,
+            None => {
+                debug!("Message not sent to a channel, not relaying");
+                return vec![];
+            }
+        };
+        let mut relayed = vec![];
+        for route in self.routing.routes(&source) {
+            if route.to == source || !self.relays_to(msg, &route.to.transport) {
+                continue;
             }
-            TransportType::Telegram => {
-                debug!("Sending message to IRC chan");
-                irc_sender.send(Message::new(
-                    TransportType::Telegram,
-                    msg.strip_command(self.command_prefix),
+            // the nicknames depend on the transport the copy goes to
+            let mut converted = msg.clone();
+            converted.convert_nicknames(&self.nicknames, route.to.transport);
+            let text = match route.tag {
+                Some(ref tag) => {
+                    format!("[{}] {}", tag, converted.strip_command(&self.command_prefix))
+                }
+                None => converted.strip_command(&self.command_prefix),
+            };
+            relayed.push((
+                route.to.transport,
+                Message::new(
+                    msg.from_transport,
+                    text,
                     msg.from.clone(),
-                    destination_irc,
+                    DestinationType::Channel(route.to.channel.clone()),
                     msg.is_from_command,
-                ));
-            }
+                ).with_type(msg.message_type),
+            ));
+        }
+        relayed
+    }
+}
+
+impl Command for RelayMessageCommand {
+    fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
+        for (transport, relayed) in self.relayed(msg) {
+            transports.send(&transport, relayed);
         }
     }
 
@@ -74,7 +107,7 @@ If the relay is configured with allow_receive set to false then only messages th
 will be relayed. So for example if you have allow_receive set to false for the telegram tranport\
 you will need to use\
     !tg <message>\
-for message to be delivered to the chat. Similarly, use !irc for IRC.",
+for message to be delivered to the chat. Similarly, use !irc for IRC, !matrix for Matrix and !xmpp for XMPP.",
         )
     }
 
@@ -83,25 +116,146 @@ for message to be delivered to the chat. Similarly, use !irc for IRC.",
     }
 
     fn matches_message_text(&self, message: &Message) -> bool {
-        match message.from_transport {
-            TransportType::IRC => {
-                if *self.telegram_allow_receive || message.is_from_command {
-                    return true;
-                }
-            }
-            TransportType::Telegram => {
-                if *self.irc_allow_receive || message.is_from_command {
-                    return true;
-                }
+        match RelayMessageCommand::source(message) {
+            Some(source) => {
+                self.routing.routes(&source).iter().any(|route| {
+                    self.relays_to(message, &route.to.transport)
+                })
             }
-        };
-        let re = Regex::new(
-            format!(r"^({})(irc|tg)\s+(.*)$", self.command_prefix).as_ref(),
-        ).unwrap();
-        re.is_match(&message.text)
+            None => false,
+        }
     }
 
     fn stop_processing(&self, _: &Message) -> bool {
         true
     }
+
+    // the routes are kept: the transports only join the channels of the bridges at startup,
+    // so a change of bridges is reported by Settings::restart_required
+    fn reload(&mut self, settings: &Settings) {
+        self.allow_receive = settings.allow_receive();
+        self.command_prefix = settings.command_prefix.clone();
+        self.nicknames = settings.nicknames.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::RelayMessageCommand;
+    use commands::command_dispatcher::Command;
+    use message::{Message, TransportType, DestinationType};
+    use routing::RoutingTable;
+    use settings::{Bridge, BridgeEndpoint, NickEntry, Settings};
+
+    fn bridge(endpoints: &[(&str, &str)]) -> Bridge {
+        Bridge {
+            endpoints: endpoints
+                .iter()
+                .map(|&(transport, channel)| {
+                    BridgeEndpoint {
+                        transport: String::from(transport),
+                        channel: String::from(channel),
+                        tag: None,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn relay() -> RelayMessageCommand {
+        relay_with_nicknames(&[])
+    }
+
+    fn relay_with_nicknames(nicknames: &[NickEntry]) -> RelayMessageCommand {
+        let bridges = vec![
+            bridge(&[("irc", "#first"), ("irc", "#second"), ("telegram", "12345")]),
+        ];
+        let mut allow_receive = HashMap::new();
+        allow_receive.insert(TransportType::IRC, true);
+        allow_receive.insert(TransportType::Telegram, true);
+        RelayMessageCommand::new(allow_receive, "!", nicknames, RoutingTable::new(&bridges))
+    }
+
+    fn message(to: DestinationType) -> Message {
+        Message::new(
+            TransportType::IRC,
+            String::from("hello"),
+            String::from("user"),
+            to,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_relay_between_channels_of_one_transport() {
+        let relay = relay();
+        let msg = message(DestinationType::Channel(String::from("#first")));
+        assert!(relay.matches_message_text(&msg));
+
+        let mut destinations: Vec<(TransportType, String)> = relay
+            .relayed(&msg)
+            .into_iter()
+            .map(|(transport, relayed)| match relayed.to {
+                DestinationType::Channel(channel) => (transport, channel),
+                _ => panic!("relayed to something else than a channel"),
+            })
+            .collect();
+        destinations.sort_by_key(|&(transport, _)| transport.id());
+        assert_eq!(
+            destinations,
+            vec![
+                (TransportType::IRC, String::from("#second")),
+                (TransportType::Telegram, String::from("12345")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nicknames_per_destination() {
+        let relay = relay_with_nicknames(&[
+            NickEntry {
+                telegram: String::from("@tg_friend"),
+                irc: String::from("friend"),
+            },
+        ]);
+        let mut msg = message(DestinationType::Channel(String::from("#first")));
+        msg.text = String::from("friend: hello");
+
+        let mut texts: Vec<(TransportType, String)> = relay
+            .relayed(&msg)
+            .into_iter()
+            .map(|(transport, relayed)| (transport, relayed.text))
+            .collect();
+        texts.sort_by_key(|&(transport, _)| transport.id());
+        assert_eq!(
+            texts,
+            vec![
+                (TransportType::IRC, String::from("user: friend: hello")),
+                (TransportType::Telegram, String::from("user: @tg_friend: hello")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_private_messages_not_relayed() {
+        let relay = relay();
+        let msg = message(DestinationType::User(String::from("holysee")));
+        assert!(!relay.matches_message_text(&msg));
+        assert!(relay.relayed(&msg).is_empty());
+    }
+
+    #[test]
+    fn test_reload_keeps_bridges() {
+        let mut relay = relay();
+        let mut settings = Settings::new("config/example").unwrap();
+        settings.bridges = vec![bridge(&[("irc", "#first"), ("telegram", "12345")])];
+        relay.reload(&settings);
+
+        let msg = message(DestinationType::Channel(String::from("#first")));
+        assert_eq!(relay.relayed(&msg).len(), 2);
+        let msg = message(DestinationType::Channel(String::from("#second")));
+        assert!(relay.matches_message_text(&msg));
+    }
 }
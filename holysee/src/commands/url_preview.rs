@@ -2,40 +2,76 @@ extern crate regex;
 extern crate reqwest;
 extern crate select;
 
+use std::collections::HashMap;
 use std::io::Read;
 use std::thread;
 
 use self::select::document::Document;
 use self::select::predicate::Name;
-use chan::Sender;
 
 use message::{Message, TransportType, DestinationType};
+use routing::{RoutingTable, Endpoint};
 use commands::command_dispatcher::Command;
+use settings::Settings;
+use transport::TransportRegistry;
 
 #[derive(Debug)]
 pub struct UrlPreviewCommand {
-    telegram_allow_receive: bool,
-    irc_allow_receive: bool,
+    allow_receive: HashMap<TransportType, bool>,
+    routing: RoutingTable,
 }
 
 const NO_TITLE_FOUND_MESSAGE: &str = "No title found";
 
 impl UrlPreviewCommand {
-    pub fn new(irc_allow_receive: bool, telegram_allow_receive: bool) -> UrlPreviewCommand {
+    pub fn new(
+        allow_receive: HashMap<TransportType, bool>,
+        routing: RoutingTable,
+    ) -> UrlPreviewCommand {
         UrlPreviewCommand {
-            telegram_allow_receive,
-            irc_allow_receive,
+            allow_receive,
+            routing,
         }
     }
 
+    /// Where the preview of a link in `msg` goes: back where it was written, and to the
+    /// endpoints bridged with that channel, with their own channel ids.
+    fn destinations(&self, msg: &Message) -> Vec<(TransportType, DestinationType)> {
+        let channel = match msg.to {
+            DestinationType::Channel(ref c) => c,
+            DestinationType::User(_) => {
+                return vec![(msg.from_transport, DestinationType::User(msg.from.clone()))]
+            }
+            DestinationType::Unknown => return vec![],
+        };
+        let source = Endpoint::new(msg.from_transport, channel);
+        let mut destinations = vec![
+            (msg.from_transport, DestinationType::Channel(channel.clone())),
+        ];
+        for route in self.routing.routes(&source) {
+            if route.to == source {
+                continue;
+            }
+            if *self.allow_receive.get(&route.to.transport).unwrap_or(&false) {
+                destinations.push((
+                    route.to.transport,
+                    DestinationType::Channel(route.to.channel.clone()),
+                ));
+            } else {
+                debug!(
+                    "Not sending preview to {} due to allow_receive being false",
+                    route.to.transport.id()
+                );
+            }
+        }
+        destinations
+    }
+
     fn get(
         url: &str,
-        destination: &DestinationType,
-        from: &str,
-        to_irc: &Sender<Message>,
-        to_telegram: &Sender<Message>,
-        irc_allow_receive: bool,
-        telegram_allow_receive: bool,
+        from_transport: TransportType,
+        destinations: &[(TransportType, DestinationType)],
+        transports: &TransportRegistry,
     ) {
         let result = reqwest::get(url);
         match result {
@@ -46,7 +82,7 @@ impl UrlPreviewCommand {
                     Err(e) => error!("Error reading data from {}: {}", url, e),
                 }
                 let document = Document::from(buf.as_ref());
-                let title_irc = match document.find(Name("title")).nth(0) {
+                let title = match document.find(Name("title")).nth(0) {
                     Some(n) => {
                         match n.children().nth(0) {
                             Some(c) => c.as_text().unwrap_or(NO_TITLE_FOUND_MESSAGE),
@@ -55,41 +91,19 @@ impl UrlPreviewCommand {
                     },
                     None => NO_TITLE_FOUND_MESSAGE
                 };
-                let destination_inner = match *destination {
-                    DestinationType::Channel(ref c) => DestinationType::Channel(c.clone()),
-                    DestinationType::User(_) => DestinationType::User(from.to_string()),
-                    DestinationType::Unknown => {
-                        panic!("Serious bug in url_preview command handler")
-                    }
-                };
                 // SEND MESSAGE
-                debug!("Extracted url: {}", title_irc);
-                let title_telegram = title_irc;
-                let destination_irc: DestinationType =
-                    DestinationType::klone(&destination_inner);
-                let destination_telegram: DestinationType =
-                    DestinationType::klone(&destination_inner);
-                if irc_allow_receive {
-                    to_irc.send(Message::new(
-                        TransportType::Telegram,
-                        title_irc.to_owned(),
-                        String::from("UrlPreviewCommand"),
-                        destination_irc,
-                        true,
-                    ));
-                } else {
-                    debug!("Not sending preview to irc due to allow_receive being {}", irc_allow_receive);
-                }
-                if telegram_allow_receive {
-                    to_telegram.send(Message::new(
-                        TransportType::IRC,
-                        title_telegram.to_owned(),
-                        String::from("UrlPreviewCommand"),
-                        destination_telegram,
-                        true,
-                    ));
-                } else {
-                    debug!("Not sending preview to telegram due to allow_receive being {}", telegram_allow_receive);
+                debug!("Extracted url: {}", title);
+                for &(ref to, ref destination) in destinations {
+                    transports.send(
+                        to,
+                        Message::new(
+                            from_transport,
+                            title.to_owned(),
+                            String::from("UrlPreviewCommand"),
+                            destination.clone(),
+                            true,
+                        ),
+                    );
                 }
             }
             Err(e) => {
@@ -100,29 +114,21 @@ impl UrlPreviewCommand {
 }
 
 impl Command for UrlPreviewCommand {
-    fn execute(
-        &mut self,
-        msg: &mut Message,
-        to_irc: &Sender<Message>,
-        to_telegram: &Sender<Message>,
-    ) {
+    fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
         let re = regex::Regex::new(
             r"(https?://(?:www\.)?[-a-zA-Z0-9@:%._\+~#=]{2,256}\.[a-z]{2,6}\b(?:[-a-zA-Z0-9@:;%()\[\]{}_\+.*~#?,&//=]*))",
         ).unwrap();
 
         // COMMAND HANDLING
         let message_text = msg.text.to_owned();
-        let irc_allow_receive = self.irc_allow_receive;
-        let telegram_allow_receive = self.telegram_allow_receive;
         for cap in re.captures_iter(&message_text) {
             let url = String::from(&cap[1]);
-            let to_irc_clone = to_irc.clone();
-            let to_telegram_clone = to_telegram.clone();
-            let destination: DestinationType = DestinationType::klone(&msg.to);
-            let from: String = msg.from.clone();
+            let transports_clone = transports.clone();
+            let destinations = self.destinations(msg);
+            let from_transport = msg.from_transport;
             debug!("Previewing url {}", url);
             thread::spawn(move || {
-                UrlPreviewCommand::get(&url, &destination, &from, &to_irc_clone, &to_telegram_clone, irc_allow_receive, telegram_allow_receive)
+                UrlPreviewCommand::get(&url, from_transport, &destinations, &transports_clone)
             });
         }
     }
@@ -144,4 +150,72 @@ impl Command for UrlPreviewCommand {
     fn stop_processing(&self, _: &Message) -> bool {
         false
     }
+
+    fn reload(&mut self, settings: &Settings) {
+        self.allow_receive = settings.allow_receive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::UrlPreviewCommand;
+    use message::{Message, TransportType, DestinationType};
+    use routing::RoutingTable;
+    use settings::{Bridge, BridgeEndpoint};
+
+    fn bridge(endpoints: &[(&str, &str)]) -> Bridge {
+        Bridge {
+            endpoints: endpoints
+                .iter()
+                .map(|&(transport, channel)| {
+                    BridgeEndpoint {
+                        transport: String::from(transport),
+                        channel: String::from(channel),
+                        tag: None,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_destinations() {
+        let bridges = vec![
+            bridge(&[("irc", "#first"), ("telegram", "111")]),
+            bridge(&[("irc", "#second"), ("telegram", "222")]),
+        ];
+        let mut allow_receive = HashMap::new();
+        allow_receive.insert(TransportType::IRC, true);
+        allow_receive.insert(TransportType::Telegram, true);
+        let preview = UrlPreviewCommand::new(allow_receive, RoutingTable::new(&bridges));
+
+        let msg = Message::new(
+            TransportType::IRC,
+            String::from("see https://example.org"),
+            String::from("user"),
+            DestinationType::Channel(String::from("#first")),
+            false,
+        );
+        assert_eq!(
+            preview.destinations(&msg),
+            vec![
+                (TransportType::IRC, DestinationType::Channel(String::from("#first"))),
+                (TransportType::Telegram, DestinationType::Channel(String::from("111"))),
+            ]
+        );
+
+        let msg = Message::new(
+            TransportType::Telegram,
+            String::from("see https://example.org"),
+            String::from("user"),
+            DestinationType::User(String::from("holysee")),
+            false,
+        );
+        assert_eq!(
+            preview.destinations(&msg),
+            vec![(TransportType::Telegram, DestinationType::User(String::from("user")))]
+        );
+    }
 }
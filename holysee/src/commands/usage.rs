@@ -1,22 +1,23 @@
 extern crate regex;
 
-use chan::Sender;
 use std::collections::HashMap;
 
 use self::regex::Regex;
 
-use message::{Message, TransportType, DestinationType};
+use message::{Message, DestinationType};
 use commands::command_dispatcher::Command;
+use settings::Settings;
+use transport::TransportRegistry;
 
 #[derive(Debug)]
 pub struct UsageCommand<'a> {
-    command_prefix: &'a str,
+    command_prefix: String,
     commands: &'a HashMap<String, String>,
 }
 
 impl<'a> UsageCommand<'a> {
     pub fn new(
-        command_prefix: &'a str,
+        command_prefix: &str,
         commands: &'a mut HashMap<String, String>,
     ) -> UsageCommand<'a> {
         debug!(
@@ -24,19 +25,14 @@ impl<'a> UsageCommand<'a> {
             commands.keys().collect::<Vec<&String>>()
         );
         UsageCommand {
-            command_prefix,
+            command_prefix: String::from(command_prefix),
             commands,
         }
     }
 }
 
 impl<'a> Command for UsageCommand<'a> {
-    fn execute(
-        &mut self,
-        msg: &mut Message,
-        to_irc: &Sender<Message>,
-        to_telegram: &Sender<Message>,
-    ) {
+    fn execute(&mut self, msg: &mut Message, transports: &TransportRegistry) {
         let re_self = Regex::new(
             format!(r"^(?:{})(?:[uU]sage|[hH]elp)$", &self.command_prefix).as_ref(),
         ).unwrap();
@@ -69,37 +65,22 @@ impl<'a> Command for UsageCommand<'a> {
             }
         }
 
-
-        let usage_string_irc = usage_string.clone();
-        let usage_string_telegram = usage_string.clone();
         let destination = match msg.to {
             DestinationType::Channel(ref c) => DestinationType::Channel(c.clone()),
             DestinationType::User(_) => DestinationType::User(msg.from.clone()),
             DestinationType::Unknown => panic!("Serious bug in usage command handler"),
         };
-        let destination_irc: DestinationType = DestinationType::klone(&destination);
-        let destination_telegram: DestinationType = DestinationType::klone(&destination);
         // SEND MESSAGES
-        match msg.from_transport {
-            TransportType::IRC => {
-                to_irc.send(Message::new(
-                    TransportType::Telegram,
-                    usage_string_irc,
-                    String::from("UsageCommand"),
-                    destination_irc,
-                    true,
-                ));
-            }
-            TransportType::Telegram => {
-                to_telegram.send(Message::new(
-                    TransportType::IRC,
-                    usage_string_telegram,
-                    String::from("UsageCommand"),
-                    destination_telegram,
-                    true,
-                ));
-            }
-        }
+        transports.send(
+            &msg.from_transport,
+            Message::new(
+                msg.from_transport,
+                usage_string,
+                String::from("UsageCommand"),
+                destination,
+                true,
+            ),
+        );
     }
 
     fn get_usage(&self) -> String {
@@ -125,4 +106,8 @@ impl<'a> Command for UsageCommand<'a> {
     fn stop_processing(&self, _: &Message) -> bool {
         true
     }
+
+    fn reload(&mut self, settings: &Settings) {
+        self.command_prefix = settings.command_prefix.clone();
+    }
 }
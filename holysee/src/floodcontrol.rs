@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use settings::IrcFlood;
+
+// a warning is logged every time the queued lines grow by this many
+const QUEUE_WARN_STEP: usize = 10;
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Allows `burst` lines at once, then one line every `refill`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    burst: f64,
+    tokens: f64,
+    refill: Duration,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: usize, refill: Duration, now: Instant) -> TokenBucket {
+        // a bucket that never holds a whole token would never let anything out
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            burst,
+            tokens: burst,
+            refill,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last {
+            return;
+        }
+        let refill = secs(self.refill);
+        let elapsed = secs(now.duration_since(self.last));
+        self.tokens = if refill == 0.0 {
+            self.burst
+        } else {
+            (self.tokens + elapsed / refill).min(self.burst)
+        };
+        self.last = now;
+    }
+
+    /// How long to wait before a line can be sent, None if it can be sent right away.
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        let missing = secs(self.refill) * (1.0 - self.tokens);
+        let nanos = (missing * 1_000_000_000.0).ceil() as u64;
+        Some(Duration::new(
+            nanos / 1_000_000_000,
+            (nanos % 1_000_000_000) as u32,
+        ))
+    }
+
+    /// Spends a token, the caller checked with wait() that one is available.
+    pub fn take(&mut self, now: Instant) {
+        self.refill(now);
+        self.tokens -= 1.0;
+    }
+}
+
+/// A line ready to be sent to an IRC target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutLine {
+    pub target: String,
+    pub text: String,
+    pub notice: bool,
+    pub private: bool,
+}
+
+/// The lines waiting for the network budget. Private replies have their own queue and go
+/// first, so a long relay on a channel does not hold them back.
+#[derive(Debug)]
+pub struct FloodControl {
+    bucket: TokenBucket,
+    channel_queue: VecDeque<OutLine>,
+    private_queue: VecDeque<OutLine>,
+    warned_depth: usize,
+}
+
+impl FloodControl {
+    pub fn new(settings: &IrcFlood, now: Instant) -> FloodControl {
+        FloodControl {
+            bucket: TokenBucket::new(
+                settings.burst,
+                Duration::from_millis(settings.refill_ms),
+                now,
+            ),
+            channel_queue: VecDeque::new(),
+            private_queue: VecDeque::new(),
+            warned_depth: 0,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.channel_queue.len() + self.private_queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depth() == 0
+    }
+
+    pub fn push(&mut self, line: OutLine) {
+        if line.private {
+            self.private_queue.push_back(line);
+        } else {
+            self.channel_queue.push_back(line);
+        }
+        let depth = self.depth();
+        if depth >= self.warned_depth + QUEUE_WARN_STEP {
+            warn!(
+                "IRC output queue is growing: {} lines ({} to channels, {} private)",
+                depth,
+                self.channel_queue.len(),
+                self.private_queue.len()
+            );
+            self.warned_depth = depth;
+        }
+    }
+
+    /// How long to wait before the next line can be sent, None if it can go right away.
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        self.bucket.wait(now)
+    }
+
+    /// The next line to send, spending a token for it.
+    pub fn pop(&mut self, now: Instant) -> Option<OutLine> {
+        let line = match self.private_queue.pop_front() {
+            Some(line) => Some(line),
+            None => self.channel_queue.pop_front(),
+        };
+        if line.is_some() {
+            self.bucket.take(now);
+            let depth = self.depth();
+            debug!("IRC output queue depth: {}", depth);
+            if depth == 0 && self.warned_depth > 0 {
+                info!("IRC output queue drained");
+                self.warned_depth = 0;
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{TokenBucket, FloodControl, OutLine};
+    use settings::IrcFlood;
+
+    fn line(text: &str, private: bool) -> OutLine {
+        OutLine {
+            target: String::from(if private { "user" } else { "#chan" }),
+            text: String::from(text),
+            notice: false,
+            private,
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(2), now);
+        assert_eq!(bucket.wait(now), None);
+        bucket.take(now);
+        assert_eq!(bucket.wait(now), None);
+        bucket.take(now);
+        assert_eq!(bucket.wait(now), Some(Duration::from_secs(2)));
+        assert_eq!(
+            bucket.wait(now + Duration::from_secs(1)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(bucket.wait(now + Duration::from_secs(2)), None);
+        // the bucket never holds more than the burst
+        let later = now + Duration::from_secs(60);
+        bucket.take(later);
+        bucket.take(later);
+        assert!(bucket.wait(later).is_some());
+    }
+
+    #[test]
+    fn test_private_first() {
+        let now = Instant::now();
+        let settings = IrcFlood {
+            burst: 5,
+            refill_ms: 1000,
+        };
+        let mut flood = FloodControl::new(&settings, now);
+        flood.push(line("one", false));
+        flood.push(line("two", false));
+        flood.push(line("reply", true));
+        assert_eq!(flood.depth(), 3);
+
+        assert_eq!(flood.pop(now).unwrap().text, "reply");
+        assert_eq!(flood.pop(now).unwrap().text, "one");
+        assert_eq!(flood.pop(now).unwrap().text, "two");
+        assert!(flood.is_empty());
+        assert_eq!(flood.pop(now), None);
+    }
+}
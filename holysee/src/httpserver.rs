@@ -0,0 +1,325 @@
+extern crate native_tls;
+
+use std::fs::File;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use self::native_tls::TlsAcceptor;
+
+// requests are small (webhook updates, pastes), anything bigger is refused
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const READ_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// Request path without the query string.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            content_type: String::from(content_type),
+            body,
+        }
+    }
+
+    pub fn text(status: u16, text: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", text.as_bytes().to_vec())
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "not found")
+    }
+}
+
+type Handler = Fn(&Request) -> Response + Send + Sync;
+
+/// Tiny HTTP/1.0 server used to expose files (relayed media, pastes) and to receive
+/// callbacks. Every route is a path prefix, the first matching one handles the request.
+pub struct HttpServer {
+    routes: Vec<(String, Box<Handler>)>,
+}
+
+impl HttpServer {
+    pub fn new() -> HttpServer {
+        HttpServer { routes: vec![] }
+    }
+
+    pub fn route<H>(&mut self, prefix: &str, handler: H)
+    where
+        H: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push((String::from(prefix), Box::new(handler)));
+    }
+
+    /// Binds `listen` and serves the requests on a background thread. Returns the bound
+    /// address, the port is picked by the system when `listen` has port 0.
+    pub fn start(self, listen: &str) -> io::Result<SocketAddr> {
+        self.serve(listen, None)
+    }
+
+    /// Like start(), over TLS.
+    pub fn start_tls(self, listen: &str, acceptor: TlsAcceptor) -> io::Result<SocketAddr> {
+        self.serve(listen, Some(acceptor))
+    }
+
+    fn serve(self, listen: &str, acceptor: Option<TlsAcceptor>) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(listen)?;
+        let address = listener.local_addr()?;
+        info!(
+            "Http{} server listening on {}",
+            if acceptor.is_some() { "s" } else { "" },
+            address
+        );
+        let routes = Arc::new(self.routes);
+        let acceptor = Arc::new(acceptor);
+        thread::spawn(move || for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let routes = routes.clone();
+                    let acceptor = acceptor.clone();
+                    thread::spawn(move || accept_connection(stream, &acceptor, &routes));
+                }
+                Err(e) => error!("Http connection failed: {}", e),
+            }
+        });
+        Ok(address)
+    }
+}
+
+fn status_reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn read_request<S: Read>(stream: &mut S) -> Result<Request, Box<Error>> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = match parts.next() {
+        Some(m) => String::from(m),
+        None => return Err(From::from("empty request line")),
+    };
+    let path = match parts.next() {
+        Some(p) => String::from(p.split('?').next().unwrap_or(p)),
+        None => return Err(From::from("request line without path")),
+    };
+
+    let mut headers = vec![];
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+        let header = header.trim_right();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(n) = header.find(':') {
+            headers.push((
+                String::from(header[..n].trim()),
+                String::from(header[n + 1..].trim()),
+            ));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|&&(ref n, _)| n.eq_ignore_ascii_case("content-length"))
+        .and_then(|&(_, ref v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        return Err(From::from(format!("request body too large: {} bytes", length)));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn write_response<S: Write>(stream: &mut S, response: &Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        status_reason(response.status),
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn accept_connection(
+    stream: TcpStream,
+    acceptor: &Option<TlsAcceptor>,
+    routes: &[(String, Box<Handler>)],
+) {
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))) {
+        error!("Cannot set http read timeout: {}", e);
+    }
+    match *acceptor {
+        Some(ref acceptor) => {
+            match acceptor.accept(stream) {
+                Ok(stream) => handle_connection(stream, routes),
+                Err(e) => warn!("Https handshake failed: {}", e),
+            }
+        }
+        None => handle_connection(stream, routes),
+    }
+}
+
+fn handle_connection<S: Read + Write>(mut stream: S, routes: &[(String, Box<Handler>)]) {
+    let response = match read_request(&mut stream) {
+        Ok(request) => {
+            debug!("Http request {} {}", request.method, request.path);
+            match routes.iter().find(|&&(ref prefix, _)| {
+                request.path.starts_with(prefix.as_str())
+            }) {
+                Some(&(_, ref handler)) => handler(&request),
+                None => Response::not_found(),
+            }
+        }
+        Err(e) => {
+            warn!("Invalid http request: {}", e);
+            Response::text(400, "bad request")
+        }
+    };
+    if let Err(e) = write_response(&mut stream, &response) {
+        error!("Cannot write http response: {}", e);
+    }
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "ogg" | "oga" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves `name` from `dir`. Only plain file names are accepted, so nothing outside `dir`
+/// can be reached.
+pub fn serve_file(dir: &str, name: &str) -> Response {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        return Response::not_found();
+    }
+    let mut body = vec![];
+    match File::open(format!("{}/{}", dir, name)).and_then(|mut f| f.read_to_end(&mut body)) {
+        Ok(_) => Response::new(200, content_type(name), body),
+        Err(_) => Response::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use self::tempdir::TempDir;
+
+    use super::{HttpServer, Response, serve_file, content_type};
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("abc.jpg"), "image/jpeg");
+        assert_eq!(content_type("abc.OGA"), "audio/ogg");
+        assert_eq!(content_type("abc"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_serve_file() {
+        let dir = TempDir::new("holysee_http").unwrap();
+        let dir_name = dir.path().to_str().unwrap();
+        File::create(dir.path().join("abc.txt"))
+            .unwrap()
+            .write_all(b"hello")
+            .unwrap();
+
+        let response = serve_file(dir_name, "abc.txt");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        assert_eq!(serve_file(dir_name, "missing.txt").status, 404);
+        assert_eq!(serve_file(dir_name, "../abc.txt").status, 404);
+        assert_eq!(serve_file(dir_name, ".hidden").status, 404);
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut server = HttpServer::new();
+        server.route("/echo/", |req| {
+            Response::new(200, "text/plain", req.body.clone())
+        });
+        let address = server.start("127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"POST /echo/x?y=1 HTTP/1.1\r\nContent-Length: 4\r\n\r\nping")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK"));
+        assert!(response.ends_with("\r\n\r\nping"));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /other HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 404"));
+    }
+}
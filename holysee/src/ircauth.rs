@@ -0,0 +1,408 @@
+extern crate base64;
+
+// AUTHENTICATE payloads are sent in chunks of this many bytes
+const AUTHENTICATE_CHUNK: usize = 400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    /// Account and password.
+    Plain,
+    /// The TLS client certificate (CertFP).
+    External,
+}
+
+impl Mechanism {
+    pub fn from_name(name: &str) -> Option<Mechanism> {
+        match name.to_uppercase().as_str() {
+            "PLAIN" => Some(Mechanism::Plain),
+            "EXTERNAL" => Some(Mechanism::External),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaslState {
+    Negotiating,
+    Authenticated,
+    Failed(String),
+}
+
+/// "[:prefix] COMMAND params... [:trailing]" -> (COMMAND, params with the trailing one)
+fn parse_line(line: &str) -> (String, Vec<String>) {
+    let mut rest = line.trim_right();
+    if rest.starts_with(':') {
+        rest = match rest.find(' ') {
+            Some(n) => &rest[n + 1..],
+            None => "",
+        };
+    }
+    let mut params = vec![];
+    let trailing = match rest.find(" :") {
+        Some(n) => {
+            let trailing = &rest[n + 2..];
+            rest = &rest[..n];
+            Some(trailing)
+        }
+        None => None,
+    };
+    let mut words = rest.split_whitespace();
+    let command = words.next().unwrap_or("").to_uppercase();
+    params.extend(words.map(String::from));
+    if let Some(trailing) = trailing {
+        params.push(String::from(trailing));
+    }
+    (command, params)
+}
+
+/// IRCv3 capability negotiation and SASL authentication, run while registering. The server
+/// waits for CAP END before completing the registration, so the channels are joined only
+/// once the authentication is over.
+#[derive(Debug)]
+pub struct SaslAuth {
+    mechanism: Mechanism,
+    account: String,
+    password: String,
+    caps: Vec<String>,
+    state: SaslState,
+}
+
+impl SaslAuth {
+    pub fn new(mechanism: Mechanism, account: &str, password: &str) -> SaslAuth {
+        SaslAuth {
+            mechanism,
+            account: String::from(account),
+            password: String::from(password),
+            caps: vec![],
+            state: SaslState::Negotiating,
+        }
+    }
+
+    pub fn state(&self) -> &SaslState {
+        &self.state
+    }
+
+    /// The registration lines: the capabilities are listed before NICK and USER.
+    pub fn start(&self, nickname: &str, username: &str, real_name: &str) -> Vec<String> {
+        vec![
+            String::from("CAP LS 302"),
+            format!("NICK {}", nickname),
+            format!("USER {} 0 * :{}", username, real_name),
+        ]
+    }
+
+    fn fail(&mut self, reason: &str) -> Vec<String> {
+        if self.state != SaslState::Negotiating {
+            return vec![];
+        }
+        error!("SASL {} authentication failed: {}", self.mechanism.name(), reason);
+        self.state = SaslState::Failed(String::from(reason));
+        vec![String::from("CAP END")]
+    }
+
+    /// True if the server offers SASL with our mechanism. Servers that do not list their
+    /// mechanisms get a try anyway.
+    fn offered(&self) -> bool {
+        self.caps.iter().any(|cap| {
+            if cap.eq_ignore_ascii_case("sasl") {
+                return true;
+            }
+            cap.to_lowercase().starts_with("sasl=") &&
+                cap["sasl=".len()..].split(',').any(|m| {
+                    m.eq_ignore_ascii_case(self.mechanism.name())
+                })
+        })
+    }
+
+    fn payload(&self) -> Vec<String> {
+        let encoded = match self.mechanism {
+            Mechanism::Plain => {
+                base64::encode(
+                    format!("{}\0{}\0{}", self.account, self.account, self.password).as_bytes(),
+                )
+            }
+            Mechanism::External => return vec![String::from("AUTHENTICATE +")],
+        };
+        let mut lines: Vec<String> = encoded
+            .as_bytes()
+            .chunks(AUTHENTICATE_CHUNK)
+            .map(|chunk| format!("AUTHENTICATE {}", String::from_utf8_lossy(chunk)))
+            .collect();
+        // a last chunk of full length needs an empty one to mark the end
+        if encoded.len() % AUTHENTICATE_CHUNK == 0 {
+            lines.push(String::from("AUTHENTICATE +"));
+        }
+        lines
+    }
+
+    /// True when NickServ is used because SASL could not be.
+    fn uses_nickserv(&self) -> bool {
+        match self.state {
+            SaslState::Failed(_) => {
+                self.mechanism == Mechanism::Plain && !self.password.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// NickServ identification, for when SASL could not be used.
+    fn nickserv_fallback(&self) -> Vec<String> {
+        if !self.uses_nickserv() {
+            return vec![];
+        }
+        warn!("Identifying with NickServ instead of SASL");
+        vec![
+            format!(
+                "PRIVMSG NickServ :IDENTIFY {} {}",
+                self.account,
+                self.password
+            ),
+        ]
+    }
+
+    /// Handles a line from the server, returning the lines to send back.
+    pub fn handle(&mut self, line: &str) -> Vec<String> {
+        let (command, params) = parse_line(line);
+        match command.as_str() {
+            "CAP" => {
+                let subcommand = params.get(1).map(|s| s.to_uppercase()).unwrap_or_default();
+                let caps = params.last().cloned().unwrap_or_default();
+                match subcommand.as_str() {
+                    "LS" => {
+                        self.caps.extend(caps.split_whitespace().map(String::from));
+                        // "CAP * LS * :..." is continued on the next line
+                        if params.len() > 3 && params[2] == "*" {
+                            vec![]
+                        } else if self.offered() {
+                            vec![String::from("CAP REQ :sasl")]
+                        } else {
+                            self.fail("the server does not offer it")
+                        }
+                    }
+                    "ACK" if caps.split_whitespace().any(|c| c.eq_ignore_ascii_case("sasl")) => {
+                        vec![format!("AUTHENTICATE {}", self.mechanism.name())]
+                    }
+                    "NAK" => self.fail("the server refused the sasl capability"),
+                    _ => vec![],
+                }
+            }
+            "AUTHENTICATE" if params.first().map_or(false, |p| p == "+") => self.payload(),
+            // RPL_LOGGEDIN
+            "900" => {
+                info!("Logged in to IRC: {}", params.last().cloned().unwrap_or_default());
+                vec![]
+            }
+            // RPL_SASLSUCCESS
+            "903" => {
+                info!("SASL {} authentication successful", self.mechanism.name());
+                self.state = SaslState::Authenticated;
+                vec![String::from("CAP END")]
+            }
+            // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED
+            "902" | "904" | "905" | "906" => {
+                let reason = params.last().cloned().unwrap_or_default();
+                self.fail(&reason)
+            }
+            // RPL_WELCOME, the registration is over
+            "001" => {
+                if self.state == SaslState::Negotiating {
+                    self.fail("the server ignored the negotiation");
+                }
+                self.nickserv_fallback()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Join {
+    Wait,
+    Now,
+    /// Authenticating failed and there is no other way to do it.
+    Failed(String),
+}
+
+/// Whether the channels can be joined after `line`: once registered, and only after
+/// authenticating with SASL or NickServ when a password or SASL is configured. Without SASL
+/// NickServ is told `password` by the irc crate.
+pub fn join_after(line: &str, auth: &Option<SaslAuth>, password: &str) -> Join {
+    let nickserv = match *auth {
+        Some(ref auth) => auth.uses_nickserv(),
+        None => !password.is_empty(),
+    };
+    let (command, params) = parse_line(line);
+    let identified = params.last().map_or(
+        false,
+        |n| n.contains("You are now identified for"),
+    );
+    match command.as_str() {
+        // RPL_WELCOME, the registration is over
+        "001" if nickserv => Join::Wait,
+        "001" => {
+            match *auth {
+                Some(ref auth) => {
+                    match *auth.state() {
+                        SaslState::Authenticated => Join::Now,
+                        SaslState::Failed(ref reason) => Join::Failed(reason.clone()),
+                        SaslState::Negotiating => {
+                            Join::Failed(String::from("the server ignored the negotiation"))
+                        }
+                    }
+                }
+                None => Join::Now,
+            }
+        }
+        // RPL_LOGGEDIN, sent by the services after IDENTIFY
+        "900" if nickserv => Join::Now,
+        "NOTICE" if nickserv && identified => Join::Now,
+        _ => Join::Wait,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SaslAuth, SaslState, Mechanism, Join, join_after, parse_line};
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line(":irc.example.org CAP * LS :sasl multi-prefix\r\n"),
+            (
+                String::from("CAP"),
+                vec![
+                    String::from("*"),
+                    String::from("LS"),
+                    String::from("sasl multi-prefix"),
+                ]
+            )
+        );
+        assert_eq!(
+            parse_line("AUTHENTICATE +"),
+            (String::from("AUTHENTICATE"), vec![String::from("+")])
+        );
+    }
+
+    #[test]
+    fn test_plain() {
+        let mut auth = SaslAuth::new(Mechanism::Plain, "holysee", "secret");
+        assert_eq!(
+            auth.start("holysee", "bot", "holysee bot"),
+            vec!["CAP LS 302", "NICK holysee", "USER bot 0 * :holysee bot"]
+        );
+        assert!(auth.handle(":srv CAP * LS * :multi-prefix").is_empty());
+        assert_eq!(
+            auth.handle(":srv CAP * LS :sasl=EXTERNAL,PLAIN"),
+            vec!["CAP REQ :sasl"]
+        );
+        assert_eq!(
+            auth.handle(":srv CAP holysee ACK :sasl"),
+            vec!["AUTHENTICATE PLAIN"]
+        );
+        assert_eq!(
+            auth.handle("AUTHENTICATE +"),
+            vec!["AUTHENTICATE aG9seXNlZQBob2x5c2VlAHNlY3JldA=="]
+        );
+        assert_eq!(
+            auth.handle(":srv 903 holysee :SASL authentication successful"),
+            vec!["CAP END"]
+        );
+        assert_eq!(*auth.state(), SaslState::Authenticated);
+        assert!(auth.handle(":srv 001 holysee :Welcome").is_empty());
+    }
+
+    #[test]
+    fn test_external() {
+        let mut auth = SaslAuth::new(Mechanism::External, "holysee", "");
+        assert_eq!(auth.handle(":srv CAP * LS :sasl"), vec!["CAP REQ :sasl"]);
+        assert_eq!(
+            auth.handle(":srv CAP * ACK :sasl"),
+            vec!["AUTHENTICATE EXTERNAL"]
+        );
+        assert_eq!(auth.handle("AUTHENTICATE +"), vec!["AUTHENTICATE +"]);
+    }
+
+    #[test]
+    fn test_long_payload() {
+        // 300 bytes encode to exactly 400 base64 characters
+        let password = "x".repeat(300 - 2 * "holysee".len() - 2);
+        let auth = SaslAuth::new(Mechanism::Plain, "holysee", &password);
+        let lines = auth.payload();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), "AUTHENTICATE ".len() + 400);
+        assert_eq!(lines[1], "AUTHENTICATE +");
+    }
+
+    #[test]
+    fn test_fallback() {
+        // no sasl: the registration goes on and NickServ is used
+        let mut auth = SaslAuth::new(Mechanism::Plain, "holysee", "secret");
+        assert_eq!(auth.handle(":srv CAP * LS :multi-prefix"), vec!["CAP END"]);
+        assert_eq!(
+            *auth.state(),
+            SaslState::Failed(String::from("the server does not offer it"))
+        );
+        assert_eq!(
+            auth.handle(":srv 001 holysee :Welcome"),
+            vec!["PRIVMSG NickServ :IDENTIFY holysee secret"]
+        );
+
+        // wrong password
+        let mut auth = SaslAuth::new(Mechanism::Plain, "holysee", "wrong");
+        auth.handle(":srv CAP * LS :sasl");
+        auth.handle(":srv CAP * ACK :sasl");
+        assert_eq!(
+            auth.handle(":srv 904 holysee :SASL authentication failed"),
+            vec!["CAP END"]
+        );
+        assert_eq!(
+            *auth.state(),
+            SaslState::Failed(String::from("SASL authentication failed"))
+        );
+    }
+
+    #[test]
+    fn test_join_after() {
+        let welcome = ":srv 001 holysee :Welcome";
+        let identified = ":NickServ!NickServ@services. NOTICE holysee :You are now identified for \
+                          holysee.";
+
+        // authenticated with SASL
+        let mut auth = SaslAuth::new(Mechanism::Plain, "holysee", "secret");
+        auth.handle(":srv CAP * LS :sasl");
+        auth.handle(":srv CAP * ACK :sasl");
+        auth.handle(":srv 903 holysee :SASL authentication successful");
+        let auth = Some(auth);
+        assert_eq!(join_after(":srv 900 holysee :Logged in", &auth, "secret"), Join::Wait);
+        assert_eq!(join_after(welcome, &auth, "secret"), Join::Now);
+
+        // falling back to NickServ, the channels wait for it
+        let mut auth = SaslAuth::new(Mechanism::Plain, "holysee", "secret");
+        auth.handle(":srv CAP * LS :multi-prefix");
+        let auth = Some(auth);
+        assert_eq!(join_after(welcome, &auth, "secret"), Join::Wait);
+        assert_eq!(join_after(identified, &auth, "secret"), Join::Now);
+
+        // the certificate was refused, there is nothing else to try
+        let mut auth = SaslAuth::new(Mechanism::External, "holysee", "");
+        auth.handle(":srv CAP * LS :sasl");
+        auth.handle(":srv CAP * ACK :sasl");
+        auth.handle(":srv 904 holysee :SASL authentication failed");
+        assert_eq!(
+            join_after(welcome, &Some(auth), ""),
+            Join::Failed(String::from("SASL authentication failed"))
+        );
+
+        // no SASL
+        assert_eq!(join_after(welcome, &None, ""), Join::Now);
+        assert_eq!(join_after(welcome, &None, "secret"), Join::Wait);
+        assert_eq!(join_after(":srv 900 holysee :Logged in", &None, "secret"), Join::Now);
+    }
+}
@@ -2,19 +2,90 @@ pub mod client {
     extern crate irc;
     extern crate chan;
 
-    use std::{thread, time, process};
+    use std::thread;
+    use std::error::Error;
+    use std::time::{Duration, Instant};
+    use std::collections::{HashMap, HashSet};
     use std::default::Default;
+    use std::sync::{mpsc, Arc, RwLock};
     use chan::{Sender, Receiver};
 
     use self::irc::client::prelude::*;
 
-    use settings::Settings;
-    use message::{Message, TransportType, DestinationType};
+    use ircauth::{SaslAuth, Mechanism, Join, join_after};
+    use irctls;
+    use ircevents::{Announcer, IrcEvent, own_departure};
+    use settings::{Settings, Irc};
+    use message::{Message, MessageType, TransportType, DestinationType};
+    use paste::PasteStore;
+    use floodcontrol::{FloodControl, OutLine};
+    use supervisor::{Link, LinkState};
+    use transport::{Transport, Capabilities};
 
+    // 512 bytes less the trailing CRLF
+    const IRC_LINE_BYTES: usize = 510;
+    // longest hostname, used until the server tells us our own
+    const MAX_HOST_BYTES: usize = 63;
+
+    /// Text bytes available in a PRIVMSG to `target`, once the server has prefixed it with our
+    /// "nick!user@host" for the other clients.
+    fn line_budget(prefix: &str, target: &str, action: bool) -> usize {
+        let mut overhead = ":".len() + prefix.len() + " PRIVMSG ".len() + target.len() + " :".len();
+        if action {
+            overhead += "\u{1}ACTION \u{1}".len();
+        }
+        IRC_LINE_BYTES.saturating_sub(overhead)
+    }
+
+    /// Splits `text` in IRC lines of at most `budget` bytes. Every input line is sent on its
+    /// own, long lines are split on word boundaries when possible and never inside a UTF-8
+    /// sequence.
+    fn split_message(text: &str, budget: usize) -> Vec<String> {
+        let mut lines = vec![];
+        for line in text.lines() {
+            let mut rest = line.trim_right();
+            while rest.len() > budget {
+                let mut cut = budget;
+                while !rest.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                if cut == 0 {
+                    // a budget smaller than a single character, send it anyway
+                    cut = rest.chars().next().map_or(rest.len(), |c| c.len_utf8());
+                }
+                let space = if rest.as_bytes().get(cut) == Some(&b' ') {
+                    Some(cut)
+                } else {
+                    rest[..cut].rfind(' ')
+                };
+                match space {
+                    Some(space) if space > 0 => {
+                        lines.push(String::from(&rest[..space]));
+                        rest = &rest[space + 1..];
+                    }
+                    _ => {
+                        lines.push(String::from(&rest[..cut]));
+                        rest = &rest[cut..];
+                    }
+                }
+            }
+            if !rest.is_empty() {
+                lines.push(String::from(rest));
+            }
+        }
+        lines
+    }
+
+    /// Turns the messages for IRC into lines and queues them for the sender.
     fn main_to_irc_loop(
         from_main_queue: &Receiver<Message>,
-        server: &IrcServer,
+        to_sender: &mpsc::Sender<OutLine>,
         channel_name: &str,
+        own_prefix: &Arc<RwLock<Option<String>>>,
+        nickname: &str,
+        username: &str,
+        max_lines: usize,
+        paste_store: &Option<PasteStore>,
     ) {
         loop {
             let current: Option<Message> = from_main_queue.recv();
@@ -22,72 +93,82 @@ pub mod client {
             let destination: String;
             match current {
                 Some(msg) => {
-                    let message_text: &String = &msg.text;
-                    let chars: Vec<char> = message_text.chars().collect();
-                    // chunk the lines in blocks up to 100 chars and re-convert to
-                    // list of strings
-                    let mut lines = chars.chunks(200)
-                        .map(|chunk| chunk.iter().collect::<String>())
-                        .collect::<Vec<_>>();
-                    let lines_len = lines.len();
-                    let mut send_delay_ms: u64;
-                    if lines_len > 30 {
-                        error = format!("skipping message with {} lines", lines_len);
-                        error!("{}", error);
-                        // overwrite the lines vector so that we only send the notification
-                        // of the missed message to the destination
-                        lines = vec![error];
-                        send_delay_ms = 0;
-                    } else if lines_len > 5 {
-                        // empirically determined by checking the flood rates of the most common
-                        // irc networks: freenode, oftc and quakenet
-                        send_delay_ms = 1000;
-                    } else {
-                        send_delay_ms = 500;
-                    }
-                    // skip the delay if we are sending to a single user
+                    // replies to a single user have their own queue
                     let to_user: bool = match msg.to {
                         DestinationType::User(u) => {
                             debug!("Sending to user {}", u);
                             destination = u;
-                            send_delay_ms = 0;
                             true
                         }
-                        DestinationType::Unknown |
-                        DestinationType::Channel(_) => {
-                            debug!("Sending to channel {}", channel_name);
+                        DestinationType::Channel(c) => {
+                            debug!("Sending to channel {}", c);
+                            destination = c;
+                            false
+                        }
+                        DestinationType::Unknown => {
+                            debug!("Sending to default channel {}", channel_name);
                             destination = String::from(channel_name);
                             false
                         }
                     };
-                    for mut line in lines {
-                        // FIXME: there sometimes is a panic in the irc library if the
-                        // message contains a "\n", so replace it with a "."
-                        if line.contains("\n") {
-                            line = line.replace("\n", ".");
+                    let prefix = match *own_prefix.read().unwrap() {
+                        Some(ref prefix) => prefix.clone(),
+                        None => {
+                            format!(
+                                "{}!~{}@{}",
+                                nickname,
+                                username,
+                                "x".repeat(MAX_HOST_BYTES)
+                            )
                         }
-                        // if the message comes from any command always send it as notice
-                        // unless the destination is a user, in that case send via privmsg
-                        if msg.is_from_command && !to_user {
-                            match server.send_notice(&destination, &line) {
-                                Ok(_) => {
-                                    info!("IRC NOTICE sent");
-                                }
-                                Err(_) => {
-                                    error!("Could not send, server disconnected");
+                    };
+                    let is_action = msg.message_type == MessageType::Action;
+                    let budget = line_budget(&prefix, &destination, is_action);
+                    let mut lines = split_message(&msg.text, budget);
+                    let lines_len = lines.len();
+                    if lines_len > max_lines {
+                        let pasted = match *paste_store {
+                            Some(ref store) => store.store(&msg.text).map_err(|e| e.to_string()),
+                            None => Err(String::from("no paste store configured")),
+                        };
+                        // overwrite the lines vector so that we only send the first line and
+                        // the link to the rest, or the notification of the missed message
+                        lines = match pasted {
+                            Ok(url) => {
+                                let first = lines.swap_remove(0);
+                                let link = format!("({} lines) {}", lines_len, url);
+                                if first.len() + 1 + link.len() <= budget {
+                                    vec![format!("{} {}", first, link)]
+                                } else {
+                                    vec![first, link]
                                 }
                             }
-                        } else {
-                            match server.send_privmsg(&destination, &line) {
-                                Ok(_) => {
-                                    info!("IRC PRIVMSG sent");
-                                }
-                                Err(_) => {
-                                    error!("Could not send, server disconnected");
-                                }
+                            Err(e) => {
+                                error = format!("skipping message with {} lines", lines_len);
+                                error!("{}: {}", error, e);
+                                vec![error]
                             }
+                        };
+                    }
+                    // if the message comes from any command always send it as notice
+                    // unless the destination is a user, in that case send via privmsg
+                    let notice = msg.is_from_command && !to_user;
+                    for line in lines {
+                        let text = if is_action && !notice {
+                            format!("\u{1}ACTION {}\u{1}", line)
+                        } else {
+                            line
+                        };
+                        let out = OutLine {
+                            target: destination.clone(),
+                            text,
+                            notice,
+                            private: to_user,
+                        };
+                        if to_sender.send(out).is_err() {
+                            error!("IRC sender stopped, dropping message");
+                            return;
                         }
-                        thread::sleep(time::Duration::from_millis(send_delay_ms));
                     }
                 }
                 None => {
@@ -97,83 +178,413 @@ pub mod client {
         }
     }
 
-    fn irc_to_main_loop(to_main_queue: &Sender<Message>, server: &IrcServer, channel_name: &str) {
+    /// Sends the queued lines within the network budget. This is the only place writing
+    /// messages to the server, so commands and relays share the same budget.
+    fn irc_sender_loop(
+        from_main: &mpsc::Receiver<OutLine>,
+        server: &SharedServer,
+        link: &Link,
+        mut flood: FloodControl,
+    ) {
         loop {
-            match server.for_each_incoming(|m| {
-                let srcnick = match m.source_nickname() {
-                    Some(x) => String::from(x),
-                    None => String::from("undefined"),
-                };
-                match m.command {
-                    irc::proto::Command::PRIVMSG(source, message_text) => {
-                        debug!(
-                            "Incoming IRC message source: {}, text: {}, src_nick: {}",
-                            source,
-                            message_text,
-                            srcnick
-                        );
-                        let destination = if source.contains('#') {
-                            DestinationType::Channel(source)
-                        } else {
-                            DestinationType::User(source)
-                        };
-                        /* The freenode network uses a bot (freenode-connect) to handle statistics
-                         * and bot abuse. Each time a new client connects a CTCP VERSION command
-                         * should be sent, but _not_ as a CTCP but as a PRIVMSG. This is a non-standard
-                         * behaviour that, according to freenode help channel, is maintained to
-                         * try and catch non-standard bots. 
-                         */
-                        if message_text.contains("\u{1}VERSION\u{1}") {
-                            debug!("freenode-connection VERSION workaround");
-                            server.send_privmsg("freenode-connect", "holysee bot 0.1");
-                        } else {
-                            to_main_queue.send(Message::new(
+            // block for new lines only when there is nothing left to send
+            if flood.is_empty() {
+                match from_main.recv() {
+                    Ok(line) => flood.push(line),
+                    Err(_) => {
+                        info!("IRC output channel closed, stopping sender");
+                        return;
+                    }
+                }
+            }
+            while let Ok(line) = from_main.try_recv() {
+                flood.push(line);
+            }
+            // the lines wait in the queue while the connection is down
+            if link.state() != LinkState::Up {
+                if let Ok(line) = from_main.recv_timeout(Duration::from_secs(1)) {
+                    flood.push(line);
+                }
+                continue;
+            }
+            if let Some(wait) = flood.wait(Instant::now()) {
+                // keep queueing what arrives in the meanwhile, private replies may jump ahead
+                if let Ok(line) = from_main.recv_timeout(wait) {
+                    flood.push(line);
+                }
+                continue;
+            }
+            let line = match flood.pop(Instant::now()) {
+                Some(line) => line,
+                None => continue,
+            };
+            let sent = match *server.read().unwrap() {
+                Some(ref server) if line.notice => server.send_notice(&line.target, &line.text),
+                Some(ref server) => server.send_privmsg(&line.target, &line.text),
+                None => {
+                    error!("Could not send, server disconnected");
+                    continue;
+                }
+            };
+            match sent {
+                Ok(_) => {
+                    info!("IRC {} sent", if line.notice { "NOTICE" } else { "PRIVMSG" });
+                }
+                Err(_) => {
+                    error!("Could not send, server disconnected");
+                }
+            }
+        }
+    }
+
+    /// Text of a CTCP ACTION ("/me waves"), None for any other message.
+    fn ctcp_action(text: &str) -> Option<&str> {
+        if text.starts_with("\u{1}ACTION ") {
+            Some(text["\u{1}ACTION ".len()..].trim_right_matches('\u{1}'))
+        } else {
+            None
+        }
+    }
+
+    /// Nicks present in the joined channels, QUIT and NICK do not tell which channels they
+    /// concern.
+    #[derive(Debug, Default)]
+    struct Members {
+        channels: HashMap<String, HashSet<String>>,
+    }
+
+    impl Members {
+        fn join(&mut self, channel: &str, nick: &str) {
+            self.channels
+                .entry(String::from(channel))
+                .or_insert_with(HashSet::new)
+                .insert(String::from(nick));
+        }
+
+        fn part(&mut self, channel: &str, nick: &str) {
+            if let Some(nicks) = self.channels.get_mut(channel) {
+                nicks.remove(nick);
+            }
+        }
+
+        fn clear(&mut self, channel: &str) {
+            self.channels.remove(channel);
+        }
+
+        /// Removes `nick` from every channel, returning the channels it was in.
+        fn quit(&mut self, nick: &str) -> Vec<String> {
+            let mut channels = vec![];
+            for (channel, nicks) in &mut self.channels {
+                if nicks.remove(nick) {
+                    channels.push(channel.clone());
+                }
+            }
+            channels
+        }
+
+        fn rename(&mut self, old: &str, new: &str) -> Vec<String> {
+            let channels = self.quit(old);
+            for channel in &channels {
+                self.join(channel, new);
+            }
+            channels
+        }
+    }
+
+    fn send_event(events: &mpsc::Sender<IrcEvent>, event: IrcEvent) {
+        if let Err(e) = events.send(event) {
+            error!("Cannot queue IRC event: {}", e);
+        }
+    }
+
+    /// Announces a member leaving a channel. When the bot itself was parted or kicked it joins
+    /// the channel again, the relay to that channel would stop without a word otherwise.
+    fn departure(
+        server: &IrcServer,
+        members: &mut Members,
+        events: &mpsc::Sender<IrcEvent>,
+        event: IrcEvent,
+    ) -> Result<(), Box<Error>> {
+        if let Some(channel) = own_departure(&event, server.current_nickname()) {
+            warn!("Left IRC channel {}, joining it again: {:?}", channel, event);
+            members.clear(&channel);
+            server.send_join(&channel)?;
+            return Ok(());
+        }
+        match event {
+            IrcEvent::Part { ref nick, ref channel, .. } |
+            IrcEvent::Kick { ref nick, ref channel, .. } => members.part(channel, nick),
+            _ => {}
+        }
+        send_event(events, event);
+        Ok(())
+    }
+
+    fn irc_to_main_loop(
+        to_main_queue: &Sender<Message>,
+        events: &mpsc::Sender<IrcEvent>,
+        server: &IrcServer,
+        channel_names: &[String],
+        own_prefix: &Arc<RwLock<Option<String>>>,
+        auth: &mut Option<SaslAuth>,
+        password: &str,
+        link: &Link,
+    ) -> Result<(), Box<Error>> {
+        let mut members = Members::default();
+        let mut joined = false;
+        // ends the session once the server closes the connection
+        let mut failure: Option<Box<Error>> = None;
+        server.for_each_incoming(|m| {
+            let line = m.to_string();
+            if let Some(ref mut auth) = *auth {
+                for line in auth.handle(&line) {
+                    if let Err(e) = send_raw(server, &line) {
+                        error!("Cannot send SASL negotiation: {}", e);
+                    }
+                }
+            }
+            if !joined && failure.is_none() {
+                match join_after(&line, &*auth, password) {
+                    Join::Now => {
+                        joined = true;
+                        for channel in channel_names {
+                            if let Err(e) = server.send_join(channel) {
+                                failure = Some(From::from(e));
+                                break;
+                            }
+                        }
+                    }
+                    Join::Failed(reason) => {
+                        failure = Some(From::from(
+                            format!("IRC authentication failed: {}", reason),
+                        ));
+                        if let Err(e) = server.send_quit("authentication failed") {
+                            error!("Cannot quit IRC: {}", e);
+                        }
+                    }
+                    Join::Wait => {}
+                }
+            }
+            let srcnick = match m.source_nickname() {
+                Some(x) => String::from(x),
+                None => String::from("undefined"),
+            };
+            match m.command {
+                irc::proto::Command::PRIVMSG(source, message_text) => {
+                    debug!(
+                        "Incoming IRC message source: {}, text: {}, src_nick: {}",
+                        source,
+                        message_text,
+                        srcnick
+                    );
+                    let destination = if source.contains('#') {
+                        DestinationType::Channel(source)
+                    } else {
+                        DestinationType::User(source)
+                    };
+                    /* The freenode network uses a bot (freenode-connect) to handle statistics
+                     * and bot abuse. Each time a new client connects a CTCP VERSION command
+                     * should be sent, but _not_ as a CTCP but as a PRIVMSG. This is a non-standard
+                     * behaviour that, according to freenode help channel, is maintained to
+                     * try and catch non-standard bots. 
+                     */
+                    if message_text.contains("\u{1}VERSION\u{1}") {
+                        debug!("freenode-connection VERSION workaround");
+                        server.send_privmsg("freenode-connect", "holysee bot 0.1");
+                    } else if let Some(action) = ctcp_action(&message_text) {
+                        to_main_queue.send(
+                            Message::new(
                                 TransportType::IRC,
-                                message_text,
+                                String::from(action),
                                 srcnick,
                                 destination,
                                 false,
-                            ));
+                            ).with_type(MessageType::Action),
+                        );
+                    } else {
+                        to_main_queue.send(Message::new(
+                            TransportType::IRC,
+                            message_text,
+                            srcnick,
+                            destination,
+                            false,
+                        ));
+                    }
+                }
+                irc::proto::Command::INVITE(_, channel) => {
+                    debug!("Got invite for channel: {}", channel);
+                    if joined && channel_names.contains(&channel) && failure.is_none() {
+                        debug!("Chosen to join channel {}", channel);
+                        if let Err(e) = server.send_join(&channel) {
+                            failure = Some(From::from(e));
                         }
                     }
-                    irc::proto::Command::INVITE(_, channel) => {
-                        debug!("Got invite for channel: {}", channel);
-                        if channel == channel_name {
-                            debug!("Chosen to join channel {}", channel);
-                            server.send_join(&channel).unwrap();
+                }
+                irc::proto::Command::NOTICE(_, notice) => {
+                    debug!("NOTICE: {}", notice);
+                }
+                irc::proto::Command::JOIN(channel, _, _) => {
+                    if srcnick == server.current_nickname() {
+                        // back in the channels after (re)connecting
+                        link.up();
+                        // our own prefix, as the other clients see it
+                        if m.prefix.is_some() {
+                            *own_prefix.write().unwrap() = m.prefix.clone();
                         }
+                        // the member list follows in the NAMES reply
+                        members.clear(&channel);
+                    } else {
+                        members.join(&channel, &srcnick);
+                        send_event(events, IrcEvent::Join { nick: srcnick, channel });
+                    }
+                }
+                irc::proto::Command::PART(channel, reason) => {
+                    let event = IrcEvent::Part {
+                        nick: srcnick,
+                        channel,
+                        reason,
+                    };
+                    if let Err(e) = departure(server, &mut members, events, event) {
+                        failure = Some(e);
                     }
-                    irc::proto::Command::NOTICE(_, notice) => {
-                        debug!("NOTICE: {}", notice);
-                        if notice.contains("You are now identified for") {
-                            debug!("Identified successfully");
-                            server.send_join(channel_name).unwrap();
+                }
+                irc::proto::Command::QUIT(reason) => {
+                    let channels = members.quit(&srcnick);
+                    if !channels.is_empty() {
+                        send_event(
+                            events,
+                            IrcEvent::Quit {
+                                nick: srcnick,
+                                channels,
+                                reason,
+                            },
+                        );
+                    }
+                }
+                irc::proto::Command::KICK(channel, nick, reason) => {
+                    let event = IrcEvent::Kick {
+                        nick,
+                        channel,
+                        by: srcnick,
+                        reason,
+                    };
+                    if let Err(e) = departure(server, &mut members, events, event) {
+                        failure = Some(e);
+                    }
+                }
+                irc::proto::Command::NICK(new) => {
+                    let channels = members.rename(&srcnick, &new);
+                    if !channels.is_empty() {
+                        send_event(
+                            events,
+                            IrcEvent::Nick {
+                                old: srcnick,
+                                new,
+                                channels,
+                            },
+                        );
+                    }
+                }
+                irc::proto::Command::Response(
+                    irc::proto::Response::RPL_NAMREPLY,
+                    args,
+                    Some(names),
+                ) => {
+                    if let Some(channel) = args.last() {
+                        for name in names.split_whitespace() {
+                            // drop the channel mode prefixes
+                            members.join(channel, name.trim_left_matches(|c| "~&@%+".contains(c)));
                         }
                     }
-                    irc::proto::Command::MOTD(_) => {}
-                    _ => debug!("IRC message:  {:#?}", m),
-                };
-            }) {
-                Ok(item) => debug!("Item from server.for_each_incoming: {:#?}", item),
-                Err(e) => error!("Server loop exiting for reason: {:#?}", e),
+                }
+                irc::proto::Command::MOTD(_) => {}
+                _ => debug!("IRC message:  {:#?}", m),
             };
-            warn!("Restarting message receive loop");
+        })?;
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+
+    /// The connection of the current session, None while reconnecting.
+    type SharedServer = Arc<RwLock<Option<IrcServer>>>;
+
+    fn send_raw(server: &IrcServer, line: &str) -> Result<(), Box<Error>> {
+        let message: irc::proto::Message = line.parse()?;
+        server.send(message)?;
+        Ok(())
+    }
+
+    fn connect(
+        cfg: &Config,
+        settings: &Irc,
+        auth: &Option<SaslAuth>,
+    ) -> Result<IrcServer, Box<Error>> {
+        let server = if settings.ssl && irctls::needs_tunnel(settings) {
+            // TLS is ours, so the certificate options apply; irc talks to a local tunnel
+            let address = irctls::tunnel(irctls::connect(settings)?)?;
+            IrcServer::from_config(Config {
+                server: Some(address.ip().to_string()),
+                port: Some(address.port()),
+                use_ssl: Some(false),
+                ..cfg.clone()
+            })?
+        } else {
+            IrcServer::from_config(cfg.clone())?
+        };
+        match *auth {
+            // identify() would end the capability negotiation right away
+            Some(ref auth) => {
+                let registration =
+                    auth.start(&settings.nickname, &settings.username, &settings.real_name);
+                for line in registration {
+                    send_raw(&server, &line)?;
+                }
+            }
+            None => server.identify()?,
         }
+        Ok(server)
     }
 
+    /// A new SASL negotiation for every connection, None when SASL is not configured.
+    fn sasl_auth(settings: &Irc) -> Result<Option<SaslAuth>, Box<Error>> {
+        let sasl = match settings.sasl {
+            Some(ref sasl) => sasl,
+            None => return Ok(None),
+        };
+        let mechanism = match Mechanism::from_name(&sasl.mechanism) {
+            Some(mechanism) => mechanism,
+            None => {
+                return Err(From::from(format!(
+                    "unknown SASL mechanism {}, use plain or external",
+                    sasl.mechanism
+                )))
+            }
+        };
+        let account = sasl.account.as_ref().unwrap_or(&settings.nickname);
+        Ok(Some(SaslAuth::new(mechanism, account, &settings.password)))
+    }
 
-    pub fn new(settings: &Settings, to_main_queue: Sender<Message>) -> Sender<Message> {
+    fn new(settings: &Settings, to_main_queue: Sender<Message>) -> (Sender<Message>, Link) {
         // TODO: fix this hardcoded value
         let (to_irc_queue, from_main_queue) = chan::sync(100);
         let cfg = Config {
             nickname: Some(settings.irc.nickname.to_owned()),
             username: Some(settings.irc.username.to_owned()),
             realname: Some(settings.irc.real_name.to_owned()),
-            nick_password: Some(settings.irc.password.to_owned()),
+            // with SASL the NickServ fallback is handled by SaslAuth
+            nick_password: if settings.irc.sasl.is_none() {
+                Some(settings.irc.password.to_owned())
+            } else {
+                None
+            },
             server: Some(settings.irc.host.to_owned()),
             port: Some(settings.irc.port.to_owned()),
-            channels: Some(vec![settings.irc.channel.to_owned()]),
             use_ssl: Some(settings.irc.ssl.to_owned()),
+            // joined by irc_to_main_loop once authenticated, irc would join right after the MOTD
+            channels: None,
             ..Default::default()
         };
         info!(
@@ -182,31 +593,164 @@ pub mod client {
             settings.irc.port
         );
         debug!("Running from configuration: {:?}", settings);
-        let irc_to_main_server = IrcServer::from_config(cfg).unwrap();
-        match irc_to_main_server.identify() {
-            Ok(_) => {
-                info!("Connection successfull");
-            }
-            Err(e) => {
-                error!("IRC server error: {}", e);
-                process::exit(1);
-            }
-        };
-        let main_to_irc_server = irc_to_main_server.clone();
-        let irc_channel_name = settings.irc.channel.clone();
+        let irc_channel_names = settings.channels(&TransportType::IRC);
         let irc_channel_name_clone = settings.irc.channel.clone();
+        let link = Link::new(
+            TransportType::IRC,
+            irc_channel_names.clone(),
+            to_main_queue.clone(),
+        );
+        let server: SharedServer = Arc::new(RwLock::new(None));
+
+        // presence changes are announced from their own thread, which batches netsplits
+        let (to_announcer, from_irc_events) = mpsc::channel();
+        let announcer = Announcer::new(&settings.irc.announce);
+        let announcer_to_main_queue = to_main_queue.clone();
+        thread::spawn(move || {
+            announcer.run(&from_irc_events, &announcer_to_main_queue)
+        });
+
+        let own_prefix = Arc::new(RwLock::new(None));
+        let own_prefix_clone = own_prefix.clone();
+        let nickname = settings.irc.nickname.clone();
+        let username = settings.irc.username.clone();
+        let max_lines = settings.paste.max_lines;
+        // pastes are only reachable through the http server
+        let paste_store = settings.http.as_ref().map(|http| {
+            PasteStore::new(&settings.paste_dir(), &http.base_url, &settings.paste)
+        });
 
+        let supervisor_link = link.clone();
+        let current_server = server.clone();
+        let irc_settings = settings.irc.clone();
+        thread::spawn(move || {
+            supervisor_link.supervise(|| {
+                let mut auth = sasl_auth(&irc_settings)?;
+                let session_server = connect(&cfg, &irc_settings, &auth)?;
+                info!("Connection successfull");
+                *current_server.write().unwrap() = Some(session_server.clone());
+                // learnt again from our first JOIN
+                *own_prefix.write().unwrap() = None;
+                let result = irc_to_main_loop(
+                    &to_main_queue,
+                    &to_announcer,
+                    &session_server,
+                    &irc_channel_names,
+                    &own_prefix,
+                    &mut auth,
+                    &irc_settings.password,
+                    &supervisor_link,
+                );
+                *current_server.write().unwrap() = None;
+                result
+            })
+        });
+        let (to_sender, from_main) = mpsc::channel();
+        let sender_link = link.clone();
+        let flood = FloodControl::new(&settings.irc.flood, Instant::now());
         thread::spawn(move || {
-            irc_to_main_loop(&to_main_queue, &irc_to_main_server, &irc_channel_name)
+            irc_sender_loop(&from_main, &server, &sender_link, flood)
         });
         thread::spawn(move || {
             main_to_irc_loop(
                 &from_main_queue,
-                &main_to_irc_server,
+                &to_sender,
                 &irc_channel_name_clone,
+                &own_prefix_clone,
+                &nickname,
+                &username,
+                max_lines,
+                &paste_store,
             )
         });
 
-        to_irc_queue.clone()
+        (to_irc_queue.clone(), link)
+    }
+
+    pub struct IrcTransport {
+        settings: Settings,
+        to_irc_queue: Option<Sender<Message>>,
+        link: Option<Link>,
+    }
+
+    impl IrcTransport {
+        pub fn new(settings: &Settings) -> IrcTransport {
+            IrcTransport {
+                settings: settings.clone(),
+                to_irc_queue: None,
+                link: None,
+            }
+        }
+    }
+
+    impl Transport for IrcTransport {
+        fn start(&mut self, to_main_queue: Sender<Message>) {
+            let (to_irc_queue, link) = new(&self.settings, to_main_queue);
+            self.to_irc_queue = Some(to_irc_queue);
+            self.link = Some(link);
+        }
+
+        fn send(&self, msg: Message) {
+            match self.to_irc_queue {
+                Some(ref queue) => queue.send(msg),
+                None => error!("IRC transport not started, dropping message"),
+            }
+        }
+
+        fn name(&self) -> TransportType {
+            TransportType::IRC
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                notices: true,
+                private_messages: true,
+            }
+        }
+
+        fn state(&self) -> LinkState {
+            self.link.as_ref().map_or(LinkState::Down, |l| l.state())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{ctcp_action, line_budget, split_message};
+
+        #[test]
+        fn test_ctcp_action() {
+            assert_eq!(ctcp_action("\u{1}ACTION waves\u{1}"), Some("waves"));
+            // some clients leave the closing delimiter out
+            assert_eq!(ctcp_action("\u{1}ACTION waves"), Some("waves"));
+            assert_eq!(ctcp_action("\u{1}VERSION\u{1}"), None);
+            assert_eq!(ctcp_action("ACTION waves"), None);
+        }
+
+        #[test]
+        fn test_line_budget() {
+            let prefix = "holysee!~holysee@example.org";
+            assert_eq!(line_budget(prefix, "#chan", false), 510 - 1 - 28 - 9 - 5 - 2);
+            assert_eq!(line_budget(prefix, "#chan", true), 510 - 1 - 28 - 9 - 5 - 2 - 9);
+        }
+
+        #[test]
+        fn test_split_message() {
+            assert_eq!(
+                split_message("first line\nsecond line\r\n\nthird", 100),
+                vec!["first line", "second line", "third"]
+            );
+            assert_eq!(
+                split_message("some words to split", 10),
+                vec!["some words", "to split"]
+            );
+            assert_eq!(
+                split_message("averyveryverylongword", 10),
+                vec!["averyveryv", "erylongwor", "d"]
+            );
+            // "è" takes two bytes and is never cut in half
+            let lines = split_message("èèèèè", 5);
+            assert_eq!(lines, vec!["èè", "èè", "è"]);
+            assert!(lines.iter().all(|l| l.len() <= 5));
+        }
     }
 }
@@ -0,0 +1,424 @@
+extern crate chan;
+extern crate regex;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use chan::Sender;
+
+use self::regex::Regex;
+
+use message::{Message, MessageType, TransportType, DestinationType};
+use settings::IrcAnnounce;
+
+// nicks lost in a netsplit are remembered this long, to fold their rejoin in a single line
+const NETSPLIT_MEMORY_SECS: u64 = 1800;
+// nicks listed by name in a netsplit summary, the others are counted
+const NETSPLIT_NICKS_SHOWN: usize = 5;
+
+/// Presence changes seen on IRC, with the channels they concern.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IrcEvent {
+    Join { nick: String, channel: String },
+    Part {
+        nick: String,
+        channel: String,
+        reason: Option<String>,
+    },
+    Quit {
+        nick: String,
+        channels: Vec<String>,
+        reason: Option<String>,
+    },
+    Kick {
+        nick: String,
+        channel: String,
+        by: String,
+        reason: Option<String>,
+    },
+    Nick {
+        old: String,
+        new: String,
+        channels: Vec<String>,
+    },
+}
+
+/// The channel the bot left when `event` is its own part or kick: it is not a member event,
+/// the bot joins the channel again instead.
+pub fn own_departure(event: &IrcEvent, own_nick: &str) -> Option<String> {
+    match *event {
+        IrcEvent::Part {
+            ref nick,
+            ref channel,
+            ..
+        } |
+        IrcEvent::Kick {
+            ref nick,
+            ref channel,
+            ..
+        } if nick == own_nick => Some(channel.clone()),
+        _ => None,
+    }
+}
+
+/// Quits and joins collected while a netsplit (or its recovery) is going on.
+#[derive(Debug)]
+struct Batch {
+    started: Instant,
+    /// The two servers that split, None for the rejoins.
+    servers: Option<String>,
+    /// Nick and channel of every member involved.
+    members: Vec<(String, String)>,
+}
+
+/// Turns IRC events into announcements for the other transports: filters them as configured,
+/// collapses netsplits into a summary and rate limits the rest.
+pub struct Announcer {
+    settings: IrcAnnounce,
+    sent: VecDeque<Instant>,
+    split: Option<Batch>,
+    rejoin: Option<Batch>,
+    split_nicks: HashMap<String, Instant>,
+    netsplit_re: Regex,
+}
+
+fn with_reason(text: String, reason: &Option<String>) -> String {
+    match *reason {
+        Some(ref r) if !r.is_empty() => format!("{} ({})", text, r),
+        _ => text,
+    }
+}
+
+fn nick_list(nicks: &[String]) -> String {
+    if nicks.len() <= NETSPLIT_NICKS_SHOWN {
+        return nicks.join(", ");
+    }
+    format!(
+        "{} and {} more",
+        nicks[..NETSPLIT_NICKS_SHOWN].join(", "),
+        nicks.len() - NETSPLIT_NICKS_SHOWN
+    )
+}
+
+impl Announcer {
+    pub fn new(settings: &IrcAnnounce) -> Announcer {
+        Announcer {
+            settings: settings.clone(),
+            sent: VecDeque::new(),
+            split: None,
+            rejoin: None,
+            split_nicks: HashMap::new(),
+            // quit messages of a netsplit carry the names of the two servers
+            netsplit_re: Regex::new(r"^[\w*-]+(\.[\w*-]+)+ [\w*-]+(\.[\w*-]+)+$").unwrap(),
+        }
+    }
+
+    fn announcement(&self, nick: &str, channel: &str, text: String) -> Message {
+        Message::new(
+            TransportType::IRC,
+            text,
+            String::from(nick),
+            DestinationType::Channel(String::from(channel)),
+            false,
+        ).with_type(MessageType::Event)
+    }
+
+    /// True if one more announcement fits in the per minute budget.
+    fn allow(&mut self, now: Instant) -> bool {
+        if self.settings.per_minute == 0 {
+            return true;
+        }
+        while self.sent
+            .front()
+            .map_or(false, |t| now.duration_since(*t) >= Duration::from_secs(60))
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.settings.per_minute {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+
+    pub fn handle(&mut self, event: IrcEvent, now: Instant) -> Vec<Message> {
+        let mut messages = self.flush(now);
+        let announcements: Vec<Message> = match event {
+            IrcEvent::Join { nick, channel } => {
+                if !self.settings.join {
+                    vec![]
+                } else if self.split_nicks.remove(&nick).is_some() {
+                    self.rejoin
+                        .get_or_insert(Batch {
+                            started: now,
+                            servers: None,
+                            members: vec![],
+                        })
+                        .members
+                        .push((nick, channel));
+                    vec![]
+                } else {
+                    let text = format!("{} joined {}", nick, channel);
+                    vec![self.announcement(&nick, &channel, text)]
+                }
+            }
+            IrcEvent::Part {
+                nick,
+                channel,
+                reason,
+            } => {
+                if !self.settings.part {
+                    vec![]
+                } else {
+                    let text = with_reason(format!("{} left {}", nick, channel), &reason);
+                    vec![self.announcement(&nick, &channel, text)]
+                }
+            }
+            IrcEvent::Quit {
+                nick,
+                channels,
+                reason,
+            } => {
+                let servers = match reason {
+                    Some(ref r) if self.netsplit_re.is_match(r) => Some(r.clone()),
+                    _ => None,
+                };
+                if !self.settings.quit {
+                    vec![]
+                } else if servers.is_some() {
+                    self.split_nicks.insert(nick.clone(), now);
+                    let batch = self.split.get_or_insert(Batch {
+                        started: now,
+                        servers,
+                        members: vec![],
+                    });
+                    for channel in channels {
+                        batch.members.push((nick.clone(), channel));
+                    }
+                    vec![]
+                } else {
+                    let text = with_reason(format!("{} quit", nick), &reason);
+                    channels
+                        .iter()
+                        .map(|c| self.announcement(&nick, c, text.clone()))
+                        .collect()
+                }
+            }
+            IrcEvent::Kick {
+                nick,
+                channel,
+                by,
+                reason,
+            } => {
+                if !self.settings.kick {
+                    vec![]
+                } else {
+                    let text = with_reason(
+                        format!("{} was kicked from {} by {}", nick, channel, by),
+                        &reason,
+                    );
+                    vec![self.announcement(&nick, &channel, text)]
+                }
+            }
+            IrcEvent::Nick { old, new, channels } => {
+                if !self.settings.nick {
+                    vec![]
+                } else {
+                    let text = format!("{} is now known as {}", old, new);
+                    channels
+                        .iter()
+                        .map(|c| self.announcement(&new, c, text.clone()))
+                        .collect()
+                }
+            }
+        };
+        for message in announcements {
+            if self.allow(now) {
+                messages.push(message);
+            } else {
+                debug!("Announcement rate limit reached, dropping: {}", message.text);
+            }
+        }
+        messages
+    }
+
+    /// Summaries of the netsplits whose collection window is over, one line per channel.
+    pub fn flush(&mut self, now: Instant) -> Vec<Message> {
+        let window = Duration::from_secs(self.settings.netsplit_window_secs);
+        let memory = Duration::from_secs(NETSPLIT_MEMORY_SECS);
+        self.split_nicks.retain(|_, t| now.duration_since(*t) < memory);
+
+        let mut batches = vec![];
+        if self.split.as_ref().map_or(false, |b| now.duration_since(b.started) >= window) {
+            batches.extend(self.split.take());
+        }
+        if self.rejoin.as_ref().map_or(false, |b| now.duration_since(b.started) >= window) {
+            batches.extend(self.rejoin.take());
+        }
+
+        let mut messages = vec![];
+        for batch in batches {
+            let mut channels: Vec<(String, Vec<String>)> = vec![];
+            for (nick, channel) in batch.members {
+                match channels.iter().position(|&(ref c, _)| *c == channel) {
+                    Some(n) => channels[n].1.push(nick),
+                    None => channels.push((channel, vec![nick])),
+                }
+            }
+            for (channel, nicks) in channels {
+                let text = match batch.servers {
+                    Some(ref servers) => {
+                        format!("netsplit {}: {} quit", servers, nick_list(&nicks))
+                    }
+                    None => format!("netsplit over: {} rejoined", nick_list(&nicks)),
+                };
+                // summaries are not rate limited, they stand for a whole storm of events
+                messages.push(self.announcement("netsplit", &channel, text));
+            }
+        }
+        messages
+    }
+
+    pub fn run(mut self, events: &Receiver<IrcEvent>, to_main_queue: &Sender<Message>) {
+        loop {
+            let messages = match events.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => self.handle(event, Instant::now()),
+                Err(RecvTimeoutError::Timeout) => self.flush(Instant::now()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!("IRC events channel closed, stopping announcements");
+                    return;
+                }
+            };
+            for message in messages {
+                to_main_queue.send(message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Announcer, IrcEvent, own_departure};
+    use message::MessageType;
+    use settings::IrcAnnounce;
+
+    fn announce() -> IrcAnnounce {
+        IrcAnnounce {
+            join: true,
+            part: true,
+            quit: true,
+            kick: true,
+            nick: true,
+            per_minute: 3,
+            netsplit_window_secs: 5,
+        }
+    }
+
+    fn quit(nick: &str, reason: &str) -> IrcEvent {
+        IrcEvent::Quit {
+            nick: String::from(nick),
+            channels: vec![String::from("#chan")],
+            reason: Some(String::from(reason)),
+        }
+    }
+
+    fn join(nick: &str) -> IrcEvent {
+        IrcEvent::Join {
+            nick: String::from(nick),
+            channel: String::from("#chan"),
+        }
+    }
+
+    #[test]
+    fn test_announcements() {
+        let mut announcer = Announcer::new(&announce());
+        let now = Instant::now();
+
+        let messages = announcer.handle(join("alice"), now);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "alice joined #chan");
+        assert_eq!(messages[0].message_type, MessageType::Event);
+
+        let messages = announcer.handle(
+            IrcEvent::Kick {
+                nick: String::from("alice"),
+                channel: String::from("#chan"),
+                by: String::from("op"),
+                reason: Some(String::from("spam")),
+            },
+            now,
+        );
+        assert_eq!(messages[0].text, "alice was kicked from #chan by op (spam)");
+
+        let messages = announcer.handle(
+            IrcEvent::Nick {
+                old: String::from("bob"),
+                new: String::from("bobby"),
+                channels: vec![String::from("#chan")],
+            },
+            now,
+        );
+        assert_eq!(messages[0].text, "bob is now known as bobby");
+
+        // the fourth announcement in a minute is dropped
+        assert!(announcer.handle(quit("carol", "Ping timeout"), now).is_empty());
+        let later = now + Duration::from_secs(61);
+        let messages = announcer.handle(quit("carol", "Ping timeout"), later);
+        assert_eq!(messages[0].text, "carol quit (Ping timeout)");
+    }
+
+    #[test]
+    fn test_disabled_events() {
+        let mut settings = announce();
+        settings.join = false;
+        let mut announcer = Announcer::new(&settings);
+        assert!(announcer.handle(join("alice"), Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_netsplit() {
+        let mut announcer = Announcer::new(&announce());
+        let now = Instant::now();
+        for nick in &["a", "b", "c", "d", "e", "f", "g"] {
+            assert!(announcer.handle(quit(nick, "hub.example.org leaf.example.org"), now).is_empty());
+        }
+        assert!(announcer.flush(now + Duration::from_secs(1)).is_empty());
+
+        let messages = announcer.flush(now + Duration::from_secs(5));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].text,
+            "netsplit hub.example.org leaf.example.org: a, b, c, d, e and 2 more quit"
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert!(announcer.handle(join("a"), later).is_empty());
+        assert!(announcer.handle(join("b"), later).is_empty());
+        let messages = announcer.flush(later + Duration::from_secs(5));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "netsplit over: a, b rejoined");
+    }
+
+    #[test]
+    fn test_own_departure() {
+        let kick = |nick: &str| {
+            IrcEvent::Kick {
+                nick: String::from(nick),
+                channel: String::from("#chan"),
+                by: String::from("op"),
+                reason: None,
+            }
+        };
+        assert_eq!(own_departure(&kick("holysee"), "holysee"), Some(String::from("#chan")));
+        assert_eq!(own_departure(&kick("alice"), "holysee"), None);
+        let part = IrcEvent::Part {
+            nick: String::from("holysee"),
+            channel: String::from("#chan"),
+            reason: None,
+        };
+        assert_eq!(own_departure(&part, "holysee"), Some(String::from("#chan")));
+        assert_eq!(own_departure(&join("holysee"), "holysee"), None);
+    }
+}
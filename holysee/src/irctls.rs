@@ -0,0 +1,417 @@
+extern crate base64;
+extern crate native_tls;
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+extern crate openssl;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::native_tls::{Certificate, Pkcs12, TlsConnector, TlsConnectorBuilder, TlsStream};
+
+use settings::Irc;
+
+// reads from the server time out this often so the writer is never locked out for long
+const READ_TIMEOUT_MS: u64 = 200;
+const BUFFER_BYTES: usize = 4096;
+
+/// Certificates from a PEM bundle, or the single certificate of a DER file.
+pub fn parse_certificates(data: &[u8]) -> Result<Vec<Vec<u8>>, Box<Error>> {
+    let text = match ::std::str::from_utf8(data) {
+        Ok(text) if text.contains("-----BEGIN CERTIFICATE-----") => text,
+        _ => return Ok(vec![data.to_vec()]),
+    };
+    let mut certificates = vec![];
+    let mut body: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line == "-----BEGIN CERTIFICATE-----" {
+            body = Some(String::new());
+        } else if line == "-----END CERTIFICATE-----" {
+            if let Some(encoded) = body.take() {
+                certificates.push(base64::decode(&encoded)?);
+            }
+        } else if let Some(ref mut encoded) = body {
+            encoded.push_str(line);
+        }
+    }
+    if body.is_some() {
+        return Err(From::from("unterminated PEM certificate"));
+    }
+    Ok(certificates)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Box<Error>> {
+    let mut data = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("cannot read {}: {}", path, e))?;
+    Ok(data)
+}
+
+/// The certificate chain of the server is verified unless ssl_verify is off, or the
+/// certificate is pinned: the pin alone decides then, so that self-signed ones can be used.
+fn verifies_chain(settings: &Irc) -> bool {
+    settings.ssl_verify && settings.ssl_pinned_cert.is_none()
+}
+
+/// True when the certificate options need our own TLS connection, the irc crate verifies the
+/// server the usual way otherwise.
+pub fn needs_tunnel(settings: &Irc) -> bool {
+    !verifies_chain(settings) || settings.ssl_ca_file.is_some() ||
+        settings.ssl_client_cert.is_some()
+}
+
+// native-tls only skips the hostname check, the chain is left to the OpenSSL context
+#[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "ios")))]
+fn disable_verification(builder: &mut TlsConnectorBuilder) -> Result<(), Box<Error>> {
+    use self::native_tls::backend::openssl::TlsConnectorBuilderExt;
+    builder.builder_mut().builder_mut().set_verify(
+        openssl::ssl::SSL_VERIFY_NONE,
+    );
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows", target_os = "ios"))]
+fn disable_verification(_: &mut TlsConnectorBuilder) -> Result<(), Box<Error>> {
+    Err(From::from(
+        "ssl_verify = false and ssl_pinned_cert need the OpenSSL backend",
+    ))
+}
+
+fn connector(settings: &Irc) -> Result<TlsConnector, Box<Error>> {
+    let mut builder = TlsConnector::builder()?;
+    if !verifies_chain(settings) {
+        disable_verification(&mut builder)?;
+    }
+    if let Some(ref ca_file) = settings.ssl_ca_file {
+        for der in parse_certificates(&read_file(ca_file)?)? {
+            builder.add_root_certificate(Certificate::from_der(&der)?)?;
+        }
+    }
+    // CertFP: the certificate and its key, bundled in a PKCS#12 file
+    if let Some(ref client_cert) = settings.ssl_client_cert {
+        let password = settings
+            .ssl_client_cert_password
+            .as_ref()
+            .map_or("", |p| p.as_str());
+        builder.identity(Pkcs12::from_der(&read_file(client_cert)?, password)?)?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Checks the certificate of the server against the pinned one, if any.
+fn check_pin(settings: &Irc, stream: &TlsStream<TcpStream>) -> Result<(), Box<Error>> {
+    let pinned_cert = match settings.ssl_pinned_cert {
+        Some(ref pinned_cert) => pinned_cert,
+        None => return Ok(()),
+    };
+    let pinned = parse_certificates(&read_file(pinned_cert)?)?;
+    let peer = match stream.peer_certificate()? {
+        Some(certificate) => certificate.to_der()?,
+        None => return Err(From::from("the server sent no certificate")),
+    };
+    if pinned.iter().any(|der| *der == peer) {
+        Ok(())
+    } else {
+        Err(From::from(format!(
+            "the certificate of {} does not match {}",
+            settings.host,
+            pinned_cert
+        )))
+    }
+}
+
+/// Connects to the server with TLS, checking its certificate against the pinned one or
+/// verifying it, unless ssl_verify is off.
+pub fn connect(settings: &Irc) -> Result<TlsStream<TcpStream>, Box<Error>> {
+    let connector = connector(settings)?;
+    let tcp = TcpStream::connect((settings.host.as_str(), settings.port))?;
+    let stream = if verifies_chain(settings) {
+        connector.connect(&settings.host, tcp)?
+    } else {
+        if settings.ssl_pinned_cert.is_none() {
+            warn!("Not verifying the certificate of {}", settings.host);
+        }
+        connector
+            .danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(tcp)?
+    };
+    check_pin(settings, &stream)?;
+    Ok(stream)
+}
+
+fn server_to_local(server: &Arc<Mutex<TlsStream<TcpStream>>>, local: &mut TcpStream) {
+    let mut buffer = [0; BUFFER_BYTES];
+    loop {
+        let read = server.lock().unwrap().read(&mut buffer);
+        match read {
+            Ok(0) => break,
+            Ok(n) => {
+                if local.write_all(&buffer[..n]).is_err() {
+                    break;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                debug!("IRC TLS connection closed: {}", e);
+                break;
+            }
+        }
+    }
+    let _ = local.shutdown(Shutdown::Both);
+}
+
+fn local_to_server(local: &mut TcpStream, server: &Arc<Mutex<TlsStream<TcpStream>>>) {
+    let mut buffer = [0; BUFFER_BYTES];
+    loop {
+        let n = match local.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let mut server = server.lock().unwrap();
+        if server.write_all(&buffer[..n]).and_then(|_| server.flush()).is_err() {
+            break;
+        }
+    }
+    let _ = server.lock().unwrap().get_ref().shutdown(Shutdown::Both);
+}
+
+/// Inode of the socket of `/proc/net/tcp` connected from `local_port` to `remote_port`.
+#[cfg(target_os = "linux")]
+fn socket_inode(table: &str, local_port: u16, remote_port: u16) -> Option<String> {
+    let port = |address: &str| {
+        address.rsplit(':').next().and_then(
+            |p| u16::from_str_radix(p, 16).ok(),
+        )
+    };
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() > 9 && port(fields[1]) == Some(local_port) &&
+            port(fields[2]) == Some(remote_port)
+        {
+            return Some(String::from(fields[9]));
+        }
+    }
+    None
+}
+
+/// True when the other end of `local`, accepted on the loopback, is a socket of this
+/// process: any local user could connect to the tunnel otherwise.
+#[cfg(target_os = "linux")]
+fn is_own_connection(local: &TcpStream) -> io::Result<bool> {
+    let peer_port = local.peer_addr()?.port();
+    let local_port = local.local_addr()?.port();
+    let mut table = String::new();
+    File::open("/proc/net/tcp")?.read_to_string(&mut table)?;
+    let inode = match socket_inode(&table, peer_port, local_port) {
+        Some(inode) => inode,
+        None => return Ok(false),
+    };
+    let socket = format!("socket:[{}]", inode);
+    for fd in ::std::fs::read_dir("/proc/self/fd")? {
+        if let Ok(target) = ::std::fs::read_link(fd?.path()) {
+            if target.to_string_lossy() == socket {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// the owner cannot be found elsewhere, the first connection is taken: the irc client connects
+// right after the tunnel is opened
+#[cfg(not(target_os = "linux"))]
+fn is_own_connection(_: &TcpStream) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Waits for the connection of the irc client, refusing the ones of other processes where
+/// they can be told apart.
+fn accept_own(listener: &TcpListener) -> io::Result<TcpStream> {
+    loop {
+        let (local, peer) = listener.accept()?;
+        if is_own_connection(&local)? {
+            return Ok(local);
+        }
+        warn!("Refusing a connection to the IRC tunnel from {}", peer);
+    }
+}
+
+/// The irc crate cannot be given a TLS connection of ours, so it is connected in plain text
+/// to a loopback port forwarding to `stream`. Returns the address to connect to, which
+/// accepts a single connection, from this process on Linux.
+pub fn tunnel(stream: TlsStream<TcpStream>) -> Result<SocketAddr, Box<Error>> {
+    stream.get_ref().set_read_timeout(
+        Some(Duration::from_millis(READ_TIMEOUT_MS)),
+    )?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    thread::spawn(move || {
+        let accepted = accept_own(&listener);
+        drop(listener);
+        let mut local = match accepted {
+            Ok(local) => local,
+            Err(e) => {
+                error!("Cannot accept the IRC connection: {}", e);
+                return;
+            }
+        };
+        let mut local_reader = match local.try_clone() {
+            Ok(local_reader) => local_reader,
+            Err(e) => {
+                error!("Cannot use the IRC connection: {}", e);
+                return;
+            }
+        };
+        let server = Arc::new(Mutex::new(stream));
+        let reader_server = server.clone();
+        thread::spawn(move || local_to_server(&mut local_reader, &reader_server));
+        server_to_local(&server, &mut local);
+    });
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use std::net::TcpStream;
+
+    use super::native_tls::{Pkcs12, TlsAcceptor};
+    use super::{connect, needs_tunnel, parse_certificates, tunnel};
+    #[cfg(target_os = "linux")]
+    use super::{is_own_connection, socket_inode};
+    use settings::{Irc, IrcAnnounce, IrcFlood};
+
+    /// Local stand-in IRC server with a self-signed certificate, greeting every client.
+    fn stand_in_server() -> u16 {
+        let identity = Pkcs12::from_der(include_bytes!("../fixtures/irc_server.p12"), "holysee")
+            .unwrap();
+        let acceptor = TlsAcceptor::builder(identity).unwrap().build().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || for stream in listener.incoming() {
+            if let Ok(mut stream) = acceptor.accept(stream.unwrap()) {
+                let _ = stream.write_all(b"hello");
+                // then echoes what the client sends
+                let mut buffer = [0; 64];
+                while let Ok(n) = stream.read(&mut buffer) {
+                    if n == 0 || stream.write_all(&buffer[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        port
+    }
+
+    fn settings(port: u16, ssl_verify: bool, ssl_pinned_cert: Option<&str>) -> Irc {
+        Irc {
+            nickname: String::from("holysee"),
+            username: String::from("holysee"),
+            real_name: String::from("holysee bot"),
+            password: String::new(),
+            host: String::from("127.0.0.1"),
+            port,
+            channel: String::from("#example"),
+            ssl: true,
+            ssl_verify,
+            ssl_ca_file: None,
+            ssl_client_cert: None,
+            ssl_client_cert_password: None,
+            ssl_pinned_cert: ssl_pinned_cert.map(String::from),
+            allow_receive: true,
+            announce: IrcAnnounce::default(),
+            flood: IrcFlood::default(),
+            sasl: None,
+        }
+    }
+
+    #[test]
+    fn test_connect_self_signed() {
+        let port = stand_in_server();
+        assert!(connect(&settings(port, true, None)).is_err());
+
+        let mut stream = connect(&settings(port, false, None)).unwrap();
+        let mut greeting = [0; 5];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        assert!(connect(&settings(port, true, Some("fixtures/irc_server.pem"))).is_ok());
+        assert!(connect(&settings(port, true, Some("fixtures/irc_other.pem"))).is_err());
+    }
+
+    #[test]
+    fn test_needs_tunnel() {
+        assert!(!needs_tunnel(&settings(6697, true, None)));
+        assert!(needs_tunnel(&settings(6697, false, None)));
+        assert!(needs_tunnel(&settings(6697, true, Some("irc-server.pem"))));
+        let mut with_ca = settings(6697, true, None);
+        with_ca.ssl_ca_file = Some(String::from("irc-ca.pem"));
+        assert!(needs_tunnel(&with_ca));
+    }
+
+    #[test]
+    fn test_tunnel() {
+        let port = stand_in_server();
+        let stream = connect(&settings(port, true, Some("fixtures/irc_server.pem"))).unwrap();
+        let address = tunnel(stream).unwrap();
+
+        let mut local = TcpStream::connect(address).unwrap();
+        let mut greeting = [0; 5];
+        local.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"hello");
+        local.write_all(b"PING :tunnel\r\n").unwrap();
+        let mut echo = [0; 14];
+        local.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"PING :tunnel\r\n");
+
+        // a single connection is accepted
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn test_parse_certificates() {
+        let pem = b"subject=/CN=irc.example.org\n\
+                    -----BEGIN CERTIFICATE-----\n\
+                    aG9s\n\
+                    eXNl\n\
+                    -----END CERTIFICATE-----\n\
+                    -----BEGIN CERTIFICATE-----\n\
+                    Ym90\n\
+                    -----END CERTIFICATE-----\n";
+        assert_eq!(
+            parse_certificates(pem).unwrap(),
+            vec![b"holyse".to_vec(), b"bot".to_vec()]
+        );
+
+        // anything else is taken as a single DER certificate
+        let der = [0x30, 0x82, 0x01, 0x0a];
+        assert_eq!(parse_certificates(&der).unwrap(), vec![der.to_vec()]);
+
+        assert!(parse_certificates(b"-----BEGIN CERTIFICATE-----\naG9s\n").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_own_connection() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   \
+                     uid  timeout inode\n   \
+                     0: 0100007F:A1B2 0100007F:1F90 01 00000000:00000000 00:00000000 00000000     \
+                     0        0 123456 1 0000000000000000 20 4 30 10 -1\n";
+        assert_eq!(socket_inode(table, 0xA1B2, 0x1F90), Some(String::from("123456")));
+        assert_eq!(socket_inode(table, 0x1F90, 0xA1B2), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (local, _) = listener.accept().unwrap();
+        assert!(is_own_connection(&local).unwrap());
+    }
+}
@@ -11,17 +11,31 @@ extern crate futures;
 extern crate tokio_core;
 #[macro_use]
 extern crate chan;
+extern crate chan_signal;
 
 mod ircclient;
+mod ircevents;
+mod ircauth;
+mod irctls;
+mod floodcontrol;
 mod telegram;
+mod matrix;
+mod xmpp;
 mod settings;
 mod message;
 mod commands;
+mod transport;
+mod routing;
+mod httpserver;
+mod paste;
+mod outbox;
+mod supervisor;
+mod cli;
 
+use std::env;
 use std::process;
 use std::collections::HashMap;
 use settings::Settings;
-use message::Message;
 use commands::command_dispatcher::Command;
 use commands::last_seen::LastSeenCommand;
 use commands::relay::RelayMessageCommand;
@@ -30,14 +44,76 @@ use commands::command_dispatcher::CommandDispatcher;
 use commands::quote::QuoteCommand;
 use commands::url_preview::UrlPreviewCommand;
 use commands::usage::UsageCommand;
+use ircclient::client::IrcTransport;
+use telegram::client::TelegramTransport;
+use matrix::client::MatrixTransport;
+use xmpp::client::XmppTransport;
+use transport::TransportRegistry;
+use routing::RoutingTable;
+use httpserver::HttpServer;
+use cli::Command as CliCommand;
+use chan_signal::Signal;
+
+/// Rereads the configuration file and applies to the commands what can change while running.
+/// The sections read at startup are compared with `running`, and their changes only logged.
+/// Returns the new settings, None when the file cannot be read.
+fn reload_settings(
+    path: &str,
+    running: &Settings,
+    command_dispatcher: &mut CommandDispatcher,
+) -> Option<Settings> {
+    let mut settings = match Settings::new(path) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Cannot reload config file {}, keeping the current one: {}", path, e);
+            return None;
+        }
+    };
+    Settings::enable_default_commands(&mut settings);
+    for section in running.restart_required(&settings) {
+        warn!("Setting {} changed, requires restart", section);
+    }
+    command_dispatcher.reload(&settings);
+    info!("Reloaded config file {}", path);
+    Some(settings)
+}
 
 fn main() {
     pretty_env_logger::init().unwrap();
+    let args = match cli::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            process::exit(2)
+        }
+    };
+    match args.command {
+        CliCommand::Help => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        CliCommand::CheckConfig => {
+            let problems = Settings::check(&args.config);
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                process::exit(1)
+            }
+            println!("{}: ok", args.config);
+            return;
+        }
+        CliCommand::Run => {}
+    }
+
+    // before any thread is started, so that they all leave SIGHUP to this channel
+    let signals = chan_signal::notify(&[Signal::HUP]);
+
     let mut usage_hashmap: HashMap<String, String> = HashMap::new();
-    let mut settings = match Settings::new(true) {
+    let mut settings = match Settings::new(&args.config) {
         Ok(s) => s,
         Err(e) => {
-            error!("Error accessing config file: {}", e);
+            error!("Error accessing config file {}: {}", args.config, e);
             process::exit(1)
         }
     };
@@ -46,11 +122,32 @@ fn main() {
     Settings::enable_default_commands(&mut settings);
 
     // TODO: fix this hardcoded value
-    let (to_irc, from_irc) = chan::sync(100);
-    let (to_telegram, from_telegram) = chan::sync(100);
+    let (to_main, from_transports) = chan::sync(100);
 
-    let irc_client = ircclient::client::new(&settings, to_irc.clone());
-    let telegram_client = telegram::client::new(&settings, to_telegram.clone());
+    let mut transports = TransportRegistry::new(to_main);
+    transports.register(Box::new(IrcTransport::new(&settings)));
+    transports.register(Box::new(TelegramTransport::new(&settings)));
+    if settings.matrix.is_some() {
+        transports.register(Box::new(MatrixTransport::new(&settings)));
+    }
+    if settings.xmpp.is_some() {
+        transports.register(Box::new(XmppTransport::new(&settings)));
+    }
+
+    if let Some(ref http) = settings.http {
+        let mut http_server = HttpServer::new();
+        let media_dir = settings.media_dir();
+        http_server.route("/media/", move |req| {
+            httpserver::serve_file(&media_dir, &req.path["/media/".len()..])
+        });
+        let paste_dir = settings.paste_dir();
+        http_server.route("/paste/", move |req| {
+            httpserver::serve_file(&paste_dir, &req.path["/paste/".len()..])
+        });
+        if let Err(e) = http_server.start(&http.listen) {
+            error!("Cannot start http server on {}: {}", http.listen, e);
+        }
+    }
 
     info!("Starting Holysee");
 
@@ -61,14 +158,14 @@ fn main() {
     let mut quote_command =
         QuoteCommand::new(&settings.command_prefix, &settings.commands.data_dir);
     let mut url_preview_command = UrlPreviewCommand::new(
-        settings.irc.allow_receive,
-        settings.telegram.allow_receive,
+        settings.allow_receive(),
+        RoutingTable::new(&settings.bridges()),
     );
     let mut relay_command = RelayMessageCommand::new(
-        &settings.irc.allow_receive,
-        &settings.telegram.allow_receive,
+        settings.allow_receive(),
         &settings.command_prefix,
         &settings.nicknames,
+        RoutingTable::new(&settings.bridges()),
     );
     usage_hashmap.insert(
         karma_command.get_name().clone(),
@@ -104,35 +201,28 @@ fn main() {
 
 
     loop {
-        let mut current_message: Message;
         chan_select! {
-            from_irc.recv() -> irc_answer => {
-                match irc_answer {
-                    Some(msg) => {
-                        debug!("Received one message from IRC chan");
-                        current_message = msg;
-                    },
-                    None => {
-                        error!("Channel disconnected!");
-                        continue
-                    },
-                };
+            signals.recv() => {
+                info!("SIGHUP received, reloading config file {}", args.config);
+                if let Some(reloaded) =
+                    reload_settings(&args.config, &settings, &mut command_dispatcher)
+                {
+                    settings = reloaded;
+                }
+            },
+            from_transports.recv() -> msg => {
+                match msg {
+                    Some(mut current_message) => {
+                        debug!(
+                            "Received one message from {} chan",
+                            current_message.from_transport.id()
+                        );
+                        debug!("Current HolySee message: {:#?}", current_message);
+                        command_dispatcher.execute(&mut current_message, &transports);
+                    }
+                    None => error!("Channel disconnected!"),
+                }
             },
-            from_telegram.recv() -> telegram_answer => {
-                match telegram_answer {
-                    Some(msg) => {
-                        debug!("Received one message from telegram chan");
-                        current_message = msg;
-                    },
-                    None => {
-                        error!("Channel disconnected!");
-                        continue
-                    },
-                };
-            }
         }
-
-        debug!("Current HolySee message: {:#?}", current_message);
-        command_dispatcher.execute(&mut current_message, &irc_client, &telegram_client);
     }
 }
@@ -0,0 +1,511 @@
+pub mod client {
+    extern crate chan;
+    extern crate chrono;
+    extern crate reqwest;
+
+    use std::thread;
+    use std::collections::{HashMap, VecDeque};
+    use std::error::Error;
+    use std::time::{Duration, Instant};
+    use chan::{Sender, Receiver};
+
+    use self::chrono::Local;
+
+    use settings::{Settings, Matrix};
+    use message::{Message, MessageType, TransportType, DestinationType, strip_irc_formatting};
+    use supervisor::{Backoff, Link, LinkState};
+    use transport::{Transport, Capabilities};
+
+    // must stay below the 30 seconds request timeout of the reqwest client
+    const SYNC_TIMEOUT_MS: u64 = 20000;
+    // messages kept while the homeserver cannot be reached, the oldest are dropped beyond that
+    const MAX_PENDING: usize = 1000;
+    const MIN_RETRY_SECS: u64 = 1;
+    const MAX_RETRY_SECS: u64 = 300;
+
+    #[derive(Debug, Deserialize)]
+    struct SyncResponse {
+        next_batch: String,
+        #[serde(default)]
+        rooms: Rooms,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    struct Rooms {
+        #[serde(default)]
+        join: HashMap<String, JoinedRoom>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    struct JoinedRoom {
+        #[serde(default)]
+        timeline: Timeline,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    struct Timeline {
+        #[serde(default)]
+        events: Vec<RoomEvent>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct RoomEvent {
+        #[serde(rename = "type")]
+        kind: String,
+        sender: String,
+        #[serde(default)]
+        content: EventContent,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    struct EventContent {
+        msgtype: Option<String>,
+        body: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct TextContent<'a> {
+        msgtype: &'a str,
+        body: &'a str,
+    }
+
+    /// Percent-encodes the characters of room ids and aliases that are not allowed in a path.
+    fn encode_path(segment: &str) -> String {
+        segment
+            .replace('%', "%25")
+            .replace('!', "%21")
+            .replace('#', "%23")
+            .replace(':', "%3A")
+            .replace('/', "%2F")
+    }
+
+    /// "@alice:example.org" is shown as "alice" on the other transports.
+    fn localpart(user_id: &str) -> String {
+        let user = user_id.trim_left_matches('@');
+        match user.find(':') {
+            Some(n) => String::from(&user[..n]),
+            None => String::from(user),
+        }
+    }
+
+    fn sync(
+        client: &reqwest::Client,
+        matrix: &Matrix,
+        since: Option<&str>,
+        timeout_ms: u64,
+    ) -> Result<SyncResponse, Box<Error>> {
+        let mut url = format!(
+            "{}/_matrix/client/r0/sync?access_token={}&timeout={}",
+            matrix.homeserver,
+            matrix.access_token,
+            timeout_ms
+        );
+        if let Some(since) = since {
+            url = format!("{}&since={}", url, since);
+        }
+        let mut resp = client.get(&url).send()?;
+        if !resp.status().is_success() {
+            return Err(From::from(format!("sync failed with status {}", resp.status())));
+        }
+        Ok(resp.json()?)
+    }
+
+    fn join(client: &reqwest::Client, matrix: &Matrix, room: &str) -> Result<(), Box<Error>> {
+        let url = format!(
+            "{}/_matrix/client/r0/join/{}?access_token={}",
+            matrix.homeserver,
+            encode_path(room),
+            matrix.access_token
+        );
+        let resp = client.post(&url).body("{}").send()?;
+        if !resp.status().is_success() {
+            return Err(From::from(format!("join failed with status {}", resp.status())));
+        }
+        Ok(())
+    }
+
+    /// Sends a message, returning the status of the homeserver. The transaction id makes a
+    /// resend of the same message harmless.
+    fn send_text(
+        client: &reqwest::Client,
+        matrix: &Matrix,
+        room: &str,
+        txn_id: &str,
+        msgtype: &str,
+        text: &str,
+    ) -> Result<reqwest::StatusCode, Box<Error>> {
+        let url = format!(
+            "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}",
+            matrix.homeserver,
+            encode_path(room),
+            txn_id,
+            matrix.access_token
+        );
+        let content = TextContent {
+            msgtype,
+            body: text,
+        };
+        let resp = client.put(&url).json(&content).send()?;
+        Ok(resp.status())
+    }
+
+    /// Rate limits and server errors are worth another try, the other refusals are final.
+    fn retryable(status: u16) -> bool {
+        status == 429 || status >= 500
+    }
+
+    fn to_messages(sync: SyncResponse, own_user_id: &str) -> Vec<Message> {
+        let mut messages = vec![];
+        for (room_id, room) in sync.rooms.join {
+            for event in room.timeline.events {
+                if event.kind != "m.room.message" || event.sender == own_user_id {
+                    continue;
+                }
+                if event.content.msgtype != Some(String::from("m.text")) {
+                    debug!("Matrix message type {:?} != m.text", event.content.msgtype);
+                    continue;
+                }
+                let text = match event.content.body {
+                    Some(body) => body,
+                    None => continue,
+                };
+                debug!(
+                    "Incoming Matrix message room: {}, text: {}, sender: {}",
+                    room_id,
+                    text,
+                    event.sender
+                );
+                messages.push(Message::new(
+                    TransportType::Matrix,
+                    text,
+                    localpart(&event.sender),
+                    DestinationType::Channel(room_id.clone()),
+                    false,
+                ));
+            }
+        }
+        messages
+    }
+
+    /// A message on its way to Matrix, with the transaction id of all its attempts.
+    struct Outgoing {
+        room: String,
+        txn_id: String,
+        msgtype: &'static str,
+        text: String,
+    }
+
+    fn outgoing(msg: &Message, matrix: &Matrix, txn_counter: u64) -> Outgoing {
+        let room = match msg.to {
+            DestinationType::Channel(ref c) => c.clone(),
+            _ => matrix.room_id.clone(),
+        };
+        // command output is sent as notice, like on IRC
+        let msgtype = if msg.is_from_command {
+            "m.notice"
+        } else if msg.message_type == MessageType::Action {
+            "m.emote"
+        } else {
+            "m.text"
+        };
+        Outgoing {
+            room,
+            txn_id: format!("holysee{}.{}", Local::now().timestamp(), txn_counter),
+            msgtype,
+            text: strip_irc_formatting(&msg.text),
+        }
+    }
+
+    /// Sends the messages in order. A message the homeserver could not take is sent again, with
+    /// a growing delay, while the next ones wait behind it.
+    fn main_to_matrix_loop(from_main_queue: &Receiver<Message>, matrix: &Matrix) {
+        let client = reqwest::Client::new();
+        let min = Duration::from_secs(MIN_RETRY_SECS);
+        let max = Duration::from_secs(MAX_RETRY_SECS);
+        let mut backoff = Backoff::new(min, max);
+        let mut txn_counter: u64 = 0;
+        let mut pending: VecDeque<Outgoing> = VecDeque::new();
+        let mut retry_at: Option<Instant> = None;
+        loop {
+            let now = Instant::now();
+            let wait = match retry_at {
+                Some(at) if at > now => Some(at - now),
+                _ => None,
+            };
+            // keep taking messages while waiting, the relay must not block on Matrix
+            if pending.is_empty() || wait.is_some() {
+                let current = match wait {
+                    Some(wait) => {
+                        let timeout = chan::after(wait);
+                        let mut received = None;
+                        chan_select! {
+                            from_main_queue.recv() -> msg => {
+                                received = msg;
+                            },
+                            timeout.recv() => {
+                                continue;
+                            },
+                        }
+                        received
+                    }
+                    None => from_main_queue.recv(),
+                };
+                match current {
+                    Some(msg) => {
+                        txn_counter += 1;
+                        if pending.len() >= MAX_PENDING {
+                            warn!("Too many Matrix messages waiting, dropping the oldest");
+                            pending.pop_front();
+                        }
+                        pending.push_back(outgoing(&msg, matrix, txn_counter));
+                    }
+                    None => info!("No message to read on internal channel"),
+                }
+                continue;
+            }
+
+            let sent = match pending.front() {
+                Some(next) => {
+                    send_text(&client, matrix, &next.room, &next.txn_id, next.msgtype, &next.text)
+                }
+                None => continue,
+            };
+            match sent {
+                Ok(ref status) if status.is_success() => {
+                    info!("Matrix message sent");
+                    pending.pop_front();
+                    backoff = Backoff::new(min, max);
+                    retry_at = None;
+                }
+                Ok(ref status) if !retryable(status.as_u16()) => {
+                    error!("Matrix refused the message with status {}, dropping it", status);
+                    pending.pop_front();
+                }
+                Ok(status) => {
+                    let delay = backoff.next();
+                    warn!("Sending Matrix message failed with status {}, retrying", status);
+                    retry_at = Some(Instant::now() + delay);
+                }
+                Err(e) => {
+                    let delay = backoff.next();
+                    warn!("Sending Matrix message failed, retrying: {}", e);
+                    retry_at = Some(Instant::now() + delay);
+                }
+            }
+        }
+    }
+
+    fn matrix_to_main_loop(
+        to_main_queue: &Sender<Message>,
+        matrix: &Matrix,
+        rooms: &[String],
+        link: &Link,
+    ) {
+        let client = reqwest::Client::new();
+        let mut since: Option<String> = None;
+        link.supervise(|| {
+            for room in rooms {
+                match join(&client, matrix, room) {
+                    Ok(_) => info!("Joined Matrix room {}", room),
+                    Err(e) => error!("Could not join Matrix room {}: {}", room, e),
+                }
+            }
+            loop {
+                // the first sync only fetches the batch token, so the room history is not
+                // relayed. After a reconnection the sync resumes from the last batch.
+                let timeout_ms = if since.is_some() { SYNC_TIMEOUT_MS } else { 0 };
+                let resp = sync(&client, matrix, since.as_ref().map(|s| s.as_str()), timeout_ms)?;
+                link.up();
+                let first_sync = since.is_none();
+                since = Some(resp.next_batch.clone());
+                if first_sync {
+                    continue;
+                }
+                for msg in to_messages(resp, &matrix.user_id) {
+                    to_main_queue.send(msg);
+                }
+            }
+        })
+    }
+
+    fn new(settings: &Settings, to_main_queue: Sender<Message>) -> (Sender<Message>, Link) {
+        // TODO: fix this hardcoded value
+        let (to_matrix_queue, from_main_queue) = chan::sync(100);
+        let matrix = match settings.matrix {
+            Some(ref m) => m.clone(),
+            None => panic!("Matrix transport started without a [matrix] section"),
+        };
+        info!("Created matrix client for {}", matrix.homeserver);
+
+        let rooms = settings.channels(&TransportType::Matrix);
+        let link = Link::new(TransportType::Matrix, rooms.clone(), to_main_queue.clone());
+        let link_clone = link.clone();
+        let matrix_clone = matrix.clone();
+        thread::spawn(move || {
+            matrix_to_main_loop(&to_main_queue, &matrix, &rooms, &link_clone)
+        });
+        thread::spawn(move || main_to_matrix_loop(&from_main_queue, &matrix_clone));
+
+        (to_matrix_queue.clone(), link)
+    }
+
+    pub struct MatrixTransport {
+        settings: Settings,
+        to_matrix_queue: Option<Sender<Message>>,
+        link: Option<Link>,
+    }
+
+    impl MatrixTransport {
+        pub fn new(settings: &Settings) -> MatrixTransport {
+            MatrixTransport {
+                settings: settings.clone(),
+                to_matrix_queue: None,
+                link: None,
+            }
+        }
+    }
+
+    impl Transport for MatrixTransport {
+        fn start(&mut self, to_main_queue: Sender<Message>) {
+            let (to_matrix_queue, link) = new(&self.settings, to_main_queue);
+            self.to_matrix_queue = Some(to_matrix_queue);
+            self.link = Some(link);
+        }
+
+        fn send(&self, msg: Message) {
+            match self.to_matrix_queue {
+                Some(ref queue) => queue.send(msg),
+                None => error!("Matrix transport not started, dropping message"),
+            }
+        }
+
+        fn name(&self) -> TransportType {
+            TransportType::Matrix
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                notices: true,
+                private_messages: false,
+            }
+        }
+
+        fn state(&self) -> LinkState {
+            self.link.as_ref().map_or(LinkState::Down, |l| l.state())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        use super::{reqwest, sync, send_text, to_messages, localpart, retryable};
+        use message::{TransportType, DestinationType};
+        use settings::Matrix;
+
+        const SYNC_RESPONSE: &str = r#"{
+            "next_batch": "s72595_4483_1934",
+            "rooms": {
+                "join": {
+                    "!room:localhost": {
+                        "timeline": {
+                            "events": [
+                                {
+                                    "type": "m.room.message",
+                                    "sender": "@alice:localhost",
+                                    "content": {"msgtype": "m.text", "body": "hello from matrix"}
+                                },
+                                {
+                                    "type": "m.room.message",
+                                    "sender": "@holysee:localhost",
+                                    "content": {"msgtype": "m.text", "body": "relayed by the bot"}
+                                },
+                                {
+                                    "type": "m.room.message",
+                                    "sender": "@bob:localhost",
+                                    "content": {"msgtype": "m.image", "body": "cat.png"}
+                                },
+                                {
+                                    "type": "m.room.member",
+                                    "sender": "@carol:localhost",
+                                    "content": {"membership": "join"}
+                                }
+                            ]
+                        }
+                    }
+                }
+            }
+        }"#;
+
+        /// Stand-in homeserver answering a single request with a canned response.
+        fn serve_once(response: &'static str) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                ).unwrap();
+            });
+            format!("http://{}", addr)
+        }
+
+        fn settings(homeserver: String) -> Matrix {
+            Matrix {
+                homeserver,
+                access_token: String::from("token"),
+                user_id: String::from("@holysee:localhost"),
+                room_id: String::from("!room:localhost"),
+                allow_receive: true,
+            }
+        }
+
+        #[test]
+        fn test_localpart() {
+            assert_eq!(localpart("@alice:localhost"), "alice");
+            assert_eq!(localpart("alice"), "alice");
+        }
+
+        #[test]
+        fn test_sync_to_messages() {
+            let matrix = settings(serve_once(SYNC_RESPONSE));
+            let client = reqwest::Client::new();
+            let resp = sync(&client, &matrix, None, 0).unwrap();
+            assert_eq!(resp.next_batch, "s72595_4483_1934");
+
+            let messages = to_messages(resp, &matrix.user_id);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].from_transport, TransportType::Matrix);
+            assert_eq!(messages[0].text, "hello from matrix");
+            assert_eq!(messages[0].from, "alice");
+            match messages[0].to {
+                DestinationType::Channel(ref c) => assert_eq!(c, "!room:localhost"),
+                _ => panic!("message not addressed to the room"),
+            }
+        }
+
+        #[test]
+        fn test_send_text() {
+            let matrix = settings(serve_once(r#"{"event_id": "$event:localhost"}"#));
+            let client = reqwest::Client::new();
+            let status = send_text(&client, &matrix, "!room:localhost", "txn1", "m.text", "hello");
+            assert!(status.unwrap().is_success());
+        }
+
+        #[test]
+        fn test_retryable() {
+            assert!(retryable(429));
+            assert!(retryable(502));
+            assert!(!retryable(403));
+            assert!(!retryable(400));
+        }
+    }
+}
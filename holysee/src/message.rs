@@ -1,15 +1,40 @@
 extern crate regex;
 
+use std::iter::Peekable;
+
 use self::regex::Regex;
 use settings::NickEntry;
 
-#[derive(Debug)]
-pub enum TransportType {
-    IRC,
-    Telegram,
+/// Identifier of the transport a message comes from. Transports are registered by this id in
+/// the `TransportRegistry`, so commands can answer on the same network without knowing which
+/// networks exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransportType(pub &'static str);
+
+#[allow(non_upper_case_globals)]
+impl TransportType {
+    pub const IRC: TransportType = TransportType("irc");
+    pub const Telegram: TransportType = TransportType("telegram");
+    pub const Matrix: TransportType = TransportType("matrix");
+    pub const XMPP: TransportType = TransportType("xmpp");
+
+    pub fn id(&self) -> &'static str {
+        self.0
+    }
+
+    pub fn from_id(id: &str) -> Option<TransportType> {
+        [
+            TransportType::IRC,
+            TransportType::Telegram,
+            TransportType::Matrix,
+            TransportType::XMPP,
+        ].iter()
+            .find(|t| t.0 == id)
+            .cloned()
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DestinationType {
     Channel(String),
     User(String),
@@ -26,13 +51,26 @@ impl DestinationType {
     }
 }
 
-#[derive(Debug)]
+/// What a message stands for, the relay renders each type differently.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MessageType {
+    Text,
+    /// New version of a message already relayed, the text is the new one or a short diff.
+    Correction,
+    /// Announcement of something that happened to `from`, like joining a channel.
+    Event,
+    /// Something `from` does, like IRC "/me waves". Transports render it natively.
+    Action,
+}
+
+#[derive(Debug, Clone)]
 pub struct Message {
     pub from_transport: TransportType,
     pub text: String,
     pub from: String,
     pub to: DestinationType,
     pub is_from_command: bool,
+    pub message_type: MessageType,
 }
 
 impl Message {
@@ -49,14 +87,25 @@ impl Message {
             from,
             to,
             is_from_command,
+            message_type: MessageType::Text,
         }
     }
+
+    pub fn with_type(mut self, message_type: MessageType) -> Message {
+        self.message_type = message_type;
+        self
+    }
+
     // TODO: sanitize this senseless abuse
     // TODO: handle symbol command for command name
     pub fn strip_command(&self, command_prefix: &str) -> String {
         let re = Regex::new(format!(r"^({})\w+\s", command_prefix).as_ref()).unwrap();
-        if self.is_from_command {
+        if self.is_from_command || self.message_type == MessageType::Event {
             format!("{}", re.replace_all(&self.text, ""))
+        } else if self.message_type == MessageType::Correction {
+            format!("* {} corrected: {}", self.from, self.text)
+        } else if self.message_type == MessageType::Action {
+            format!("{} {}", self.from, self.text)
         } else {
             format!("{}: {}", self.from, re.replace_all(&self.text, ""))
         }
@@ -64,12 +113,10 @@ impl Message {
 
     fn nickname_needs_conversion(&self, irc_nick: &str, telegram_nick: &str) -> bool {
         let nick = match self.from_transport {
-            TransportType::IRC => {
-                irc_nick
-            },
-            TransportType::Telegram => {
-                telegram_nick
-            }
+            // XMPP nicknames share the IRC side of the nicknames map
+            TransportType::IRC | TransportType::XMPP => irc_nick,
+            TransportType::Telegram => telegram_nick,
+            _ => return false,
         };
         let column_mention = format!("{}:", nick);
         let at_mention = format!("@{}", nick);
@@ -84,39 +131,239 @@ impl Message {
     }
 
     // TODO: refactor this interface to not depend on settings::NickEntry
-    pub fn convert_nicknames(&mut self, nicknames: &[NickEntry]) {
+    /// Replaces the nicknames mentioned in the text by the ones of the `to` transport.
+    pub fn convert_nicknames(&mut self, nicknames: &[NickEntry], to: TransportType) {
         for nick_map in nicknames {
-            match self.from_transport {
-                TransportType::IRC => {
-                    if self.nickname_needs_conversion( &nick_map.irc, &nick_map.telegram) {
-                        debug!(
-                            "Converting current irc from {} to telegram {}",
-                            self.from,
-                            nick_map.telegram
-                        );
-                        self.text = self.text.replace(&nick_map.irc, &nick_map.telegram);
-                    }
-                }
-                TransportType::Telegram => {
-                    if self.nickname_needs_conversion(&nick_map.irc, &nick_map.telegram) {
-                        debug!(
-                            "Converting current telegram from {} to irc {}",
-                            self.from,
-                            nick_map.irc
-                        );
-                        self.text = self.text.replace(&nick_map.telegram, &nick_map.irc);
-                    }
+            let (from_nick, to_nick) = match (
+                nickname_column(nick_map, self.from_transport),
+                nickname_column(nick_map, to),
+            ) {
+                (Some(from_nick), Some(to_nick)) if from_nick != to_nick => (from_nick, to_nick),
+                _ => continue,
+            };
+            if self.nickname_needs_conversion(&nick_map.irc, &nick_map.telegram) {
+                debug!(
+                    "Converting current {} from {} to {} {}",
+                    self.from_transport.id(),
+                    self.from,
+                    to.id(),
+                    to_nick
+                );
+                self.text = self.text.replace(from_nick, to_nick);
+            }
+        }
+    }
+}
+
+/// The column of the nicknames map used by `transport`, XMPP shares the IRC one.
+fn nickname_column(nick_map: &NickEntry, transport: TransportType) -> Option<&str> {
+    match transport {
+        TransportType::IRC | TransportType::XMPP => Some(nick_map.irc.as_str()),
+        TransportType::Telegram => Some(nick_map.telegram.as_str()),
+        _ => None,
+    }
+}
+
+// mIRC formatting control codes
+const BOLD: char = '\u{2}';
+const COLOR: char = '\u{3}';
+const HEX_COLOR: char = '\u{4}';
+const RESET: char = '\u{f}';
+const MONOSPACE: char = '\u{11}';
+const REVERSE: char = '\u{16}';
+const ITALIC: char = '\u{1d}';
+const STRIKETHROUGH: char = '\u{1e}';
+const UNDERLINE: char = '\u{1f}';
+
+/// IRC text split in characters and the formatting toggles Telegram can render. Colors,
+/// reverse and strikethrough are dropped.
+#[derive(Debug, PartialEq)]
+enum IrcToken {
+    Text(char),
+    Bold,
+    Italic,
+    Underline,
+    Monospace,
+    Reset,
+}
+
+/// Skips the "fg[,bg]" parameters of a color code, made of up to `width` digits each.
+fn skip_color<I: Iterator<Item = char> + Clone>(chars: &mut Peekable<I>, radix: u32, width: usize) {
+    let mut digits = 0;
+    while digits < width && chars.peek().map_or(false, |c| c.is_digit(radix)) {
+        chars.next();
+        digits += 1;
+    }
+    if digits == 0 {
+        return;
+    }
+    let mut ahead = chars.clone();
+    if ahead.next() == Some(',') && ahead.next().map_or(false, |c| c.is_digit(radix)) {
+        chars.next();
+        let mut digits = 0;
+        while digits < width && chars.peek().map_or(false, |c| c.is_digit(radix)) {
+            chars.next();
+            digits += 1;
+        }
+    }
+}
+
+fn irc_tokens(text: &str) -> Vec<IrcToken> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            BOLD => tokens.push(IrcToken::Bold),
+            ITALIC => tokens.push(IrcToken::Italic),
+            UNDERLINE => tokens.push(IrcToken::Underline),
+            MONOSPACE => tokens.push(IrcToken::Monospace),
+            RESET => tokens.push(IrcToken::Reset),
+            COLOR => skip_color(&mut chars, 10, 2),
+            HEX_COLOR => skip_color(&mut chars, 16, 6),
+            REVERSE | STRIKETHROUGH => {}
+            c => tokens.push(IrcToken::Text(c)),
+        }
+    }
+    tokens
+}
+
+/// Removes every mIRC formatting code, for the transports that cannot render them.
+pub fn strip_irc_formatting(text: &str) -> String {
+    irc_tokens(text)
+        .into_iter()
+        .filter_map(|t| match t {
+            IrcToken::Text(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Converts mIRC formatting to the HTML subset understood by Telegram, escaping the text.
+pub fn irc_to_html(text: &str) -> String {
+    let mut html = String::new();
+    // tags wanted for the next character and tags actually open, in opening order
+    let (mut bold, mut italic, mut underline, mut monospace) = (false, false, false, false);
+    let mut open: Vec<&'static str> = vec![];
+    for token in irc_tokens(text) {
+        let c = match token {
+            IrcToken::Bold => {
+                bold = !bold;
+                continue;
+            }
+            IrcToken::Italic => {
+                italic = !italic;
+                continue;
+            }
+            IrcToken::Underline => {
+                underline = !underline;
+                continue;
+            }
+            IrcToken::Monospace => {
+                monospace = !monospace;
+                continue;
+            }
+            IrcToken::Reset => {
+                bold = false;
+                italic = false;
+                underline = false;
+                monospace = false;
+                continue;
+            }
+            IrcToken::Text(c) => c,
+        };
+        // tags are only opened in front of some text, so no empty element is sent
+        let wanted: Vec<&'static str> = [
+            (bold, "b"),
+            (italic, "i"),
+            (underline, "u"),
+            (monospace, "code"),
+        ].iter()
+            .filter(|&&(on, _)| on)
+            .map(|&(_, tag)| tag)
+            .collect();
+        if wanted != open {
+            for tag in open.iter().rev() {
+                html.push_str(&format!("</{}>", tag));
+            }
+            for tag in &wanted {
+                html.push_str(&format!("<{}>", tag));
+            }
+            open = wanted;
+        }
+        html.push_str(&escape_html(&c.to_string()));
+    }
+    for tag in open.iter().rev() {
+        html.push_str(&format!("</{}>", tag));
+    }
+    html
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpanStyle {
+    Bold,
+    Italic,
+    Underline,
+    Code,
+    /// Text pointing to an url.
+    Link(String),
+}
+
+/// Formatting of a part of a message. As in Telegram entities, offset and length count UTF-16
+/// code units.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub length: usize,
+    pub style: SpanStyle,
+}
+
+/// Writes the codes of the spans starting (or ending) at `position`.
+fn push_span_codes(irc: &mut String, spans: &[Span], position: usize, starting: bool) {
+    for span in spans {
+        let at = if starting { span.offset } else { span.offset + span.length };
+        if span.length == 0 || at != position {
+            continue;
+        }
+        match span.style {
+            SpanStyle::Bold => irc.push(BOLD),
+            SpanStyle::Italic => irc.push(ITALIC),
+            SpanStyle::Underline => irc.push(UNDERLINE),
+            SpanStyle::Code => irc.push(MONOSPACE),
+            SpanStyle::Link(ref url) => {
+                if !starting {
+                    irc.push_str(&format!(" <{}>", url));
                 }
             }
         }
     }
 }
 
+/// Renders formatted spans with mIRC codes. Links are written as "text <url>".
+pub fn spans_to_irc(text: &str, spans: &[Span]) -> String {
+    let mut irc = String::new();
+    let mut position = 0;
+    for c in text.chars() {
+        push_span_codes(&mut irc, spans, position, false);
+        push_span_codes(&mut irc, spans, position, true);
+        irc.push(c);
+        position += c.len_utf16();
+    }
+    push_span_codes(&mut irc, spans, position, false);
+    irc
+}
+
 #[cfg(test)]
 mod tests {
     use super::Message;
     use super::TransportType;
     use super::DestinationType;
+    use super::MessageType;
+    use settings::NickEntry;
+    use super::{irc_to_html, strip_irc_formatting, spans_to_irc, Span, SpanStyle};
 
     #[test]
     fn test_strip_command() {
@@ -128,6 +375,10 @@ mod tests {
         assert_eq!(Message::new(TransportType::IRC, String::from("at the !beginning of line"), String::from("nickname"), DestinationType::Channel(String::from("#somechan")), true).strip_command("!"), String::from("at the !beginning of line"));
         // ironic use of ! not from command, so contains the nickname
         assert_eq!(Message::new(TransportType::IRC, String::from("at the !beginning of line"), String::from("nickname"), DestinationType::Channel(String::from("#somechan")), false).strip_command("!"), String::from("nickname: at the !beginning of line"));
+        // corrections are attributed with their own wording
+        assert_eq!(Message::new(TransportType::Telegram, String::from("teh → the"), String::from("nickname"), DestinationType::Channel(String::from("-100123")), false).with_type(MessageType::Correction).strip_command("!"), String::from("* nickname corrected: teh → the"));
+        // actions are rendered by the transports, they only get the nickname
+        assert_eq!(Message::new(TransportType::IRC, String::from("waves"), String::from("nickname"), DestinationType::Channel(String::from("#somechan")), false).with_type(MessageType::Action).strip_command("!"), String::from("nickname waves"));
     }
 
     #[test]
@@ -151,4 +402,54 @@ mod tests {
         assert_eq!(Message::new(TransportType::Telegram, String::from("mentioned tg_nickname in a conversation"), String::from("nickname"), DestinationType::Channel(String::from("#somechan")), false).nickname_needs_conversion("nickname", "@tg_nickname"), false);
         assert_eq!(Message::new(TransportType::Telegram, String::from("mentioned @tg_nickname in a conversation"), String::from("nickname"), DestinationType::Channel(String::from("#somechan")), false).nickname_needs_conversion("nickname", "@tg_nickname"), true);
     }
+
+    #[test]
+    fn test_convert_nicknames() {
+        let nicknames = vec![
+            NickEntry {
+                telegram: String::from("@tg_nickname"),
+                irc: String::from("nickname"),
+            },
+        ];
+        let converted = |from_transport: TransportType, text: &str, to: TransportType| {
+            let mut msg = Message::new(from_transport, String::from(text), String::from("user"), DestinationType::Channel(String::from("#somechan")), false);
+            msg.convert_nicknames(&nicknames, to);
+            msg.text
+        };
+        assert_eq!(converted(TransportType::IRC, "nickname: hi", TransportType::Telegram), "@tg_nickname: hi");
+        assert_eq!(converted(TransportType::Telegram, "@tg_nickname hi", TransportType::IRC), "nickname hi");
+        // XMPP shares the IRC nicknames
+        assert_eq!(converted(TransportType::XMPP, "nickname: hi", TransportType::IRC), "nickname: hi");
+        assert_eq!(converted(TransportType::IRC, "nickname: hi", TransportType::XMPP), "nickname: hi");
+        assert_eq!(converted(TransportType::XMPP, "nickname: hi", TransportType::Telegram), "@tg_nickname: hi");
+        assert_eq!(converted(TransportType::Telegram, "@tg_nickname hi", TransportType::Matrix), "@tg_nickname hi");
+    }
+
+    #[test]
+    fn test_irc_to_html() {
+        assert_eq!(irc_to_html("plain <text> & more"), "plain &lt;text&gt; &amp; more");
+        assert_eq!(irc_to_html("\u{2}bold\u{2} and \u{1d}italic\u{1d}"), "<b>bold</b> and <i>italic</i>");
+        // overlapping styles are closed and reopened to keep the tags nested
+        assert_eq!(irc_to_html("\u{2}b\u{1d}bi\u{2}i\u{f}"), "<b>b</b><b><i>bi</i></b><i>i</i>");
+        // colors are dropped, digits following a color code are not eaten
+        assert_eq!(irc_to_html("\u{3}04,12red\u{3} \u{3}1234"), "red 34");
+        assert_eq!(irc_to_html("\u{2}\u{2}empty"), "empty");
+    }
+
+    #[test]
+    fn test_strip_irc_formatting() {
+        assert_eq!(strip_irc_formatting("\u{2}bold\u{f} \u{3}4red\u{1f}!"), "bold red!");
+    }
+
+    #[test]
+    fn test_spans_to_irc() {
+        let spans = vec![
+            Span { offset: 0, length: 4, style: SpanStyle::Bold },
+            Span { offset: 5, length: 4, style: SpanStyle::Link(String::from("https://example.org")) },
+        ];
+        assert_eq!(spans_to_irc("bold link end", &spans), "\u{2}bold\u{2} link <https://example.org> end");
+        // offsets count UTF-16 code units, the emoji takes two
+        let spans = vec![Span { offset: 3, length: 2, style: SpanStyle::Italic }];
+        assert_eq!(spans_to_irc("😀 ok", &spans), "😀 \u{1d}ok\u{1d}");
+    }
 }
@@ -0,0 +1,503 @@
+extern crate serde_json;
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+// failed sends are retried after 2, 4, 8... seconds, up to this long
+const MAX_BACKOFF_SECS: u64 = 300;
+// a message failing this many times in a row is dropped
+const MAX_ATTEMPTS: u32 = 10;
+// the journal is folded into the saved queues once it has this many entries
+const MAX_JOURNAL_ENTRIES: usize = 1000;
+
+/// What is saved in data_dir: the pending messages and the destinations that moved.
+#[derive(Debug, Serialize, Deserialize)]
+struct Database<T> {
+    queues: HashMap<String, VecDeque<T>>,
+    #[serde(default)]
+    migrated: HashMap<String, String>,
+    /// Only the journal entries of the same generation come after these queues.
+    #[serde(default)]
+    generation: u64,
+}
+
+/// A change to the queues. The changes are appended to a journal rather than saving all the
+/// queues every time.
+#[derive(Debug, Serialize, Deserialize)]
+enum Change<T> {
+    Push(String, T),
+    Pop(String),
+    Migrate(String, String),
+}
+
+/// One line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry<C> {
+    generation: u64,
+    change: C,
+}
+
+fn append<C: Serialize>(journal: &mut File, entry: &JournalEntry<C>) -> Result<(), Box<Error>> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    journal.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Pending messages by destination. A destination waiting to retry does not hold back the
+/// others, and keeps its messages in order.
+#[derive(Debug)]
+struct Queues<T> {
+    db: Database<T>,
+    retry_at: HashMap<String, Instant>,
+    attempts: HashMap<String, u32>,
+}
+
+impl<T: Clone> Queues<T> {
+    fn new(db: Database<T>) -> Queues<T> {
+        Queues {
+            db,
+            retry_at: HashMap::new(),
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// The destination messages for `destination` are delivered to, after migrations.
+    fn resolve(&self, destination: &str) -> String {
+        let mut destination = String::from(destination);
+        while let Some(to) = self.db.migrated.get(&destination) {
+            if *to == destination {
+                break;
+            }
+            destination = to.clone();
+        }
+        destination
+    }
+
+    fn push(&mut self, destination: &str, item: T) {
+        let destination = self.resolve(destination);
+        self.db
+            .queues
+            .entry(destination)
+            .or_insert_with(VecDeque::new)
+            .push_back(item);
+    }
+
+    /// The first message of a destination that is not waiting to retry.
+    fn ready(&self, now: Instant) -> Option<(String, T)> {
+        let mut destinations: Vec<&String> = self.db.queues.keys().collect();
+        destinations.sort();
+        for destination in destinations {
+            if self.retry_at.get(destination).map_or(false, |t| *t > now) {
+                continue;
+            }
+            if let Some(item) = self.db.queues[destination].front() {
+                return Some((destination.clone(), item.clone()));
+            }
+        }
+        None
+    }
+
+    /// How long until a waiting destination can be retried, None if none is waiting.
+    fn next_retry(&self, now: Instant) -> Option<Duration> {
+        self.db
+            .queues
+            .iter()
+            .filter(|&(_, queue)| !queue.is_empty())
+            .filter_map(|(destination, _)| self.retry_at.get(destination))
+            .map(|t| if *t > now { *t - now } else { Duration::from_secs(0) })
+            .min()
+    }
+
+    fn pop(&mut self, destination: &str) {
+        let empty = match self.db.queues.get_mut(destination) {
+            Some(queue) => {
+                queue.pop_front();
+                queue.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.db.queues.remove(destination);
+        }
+        self.retry_at.remove(destination);
+        self.attempts.remove(destination);
+    }
+
+    /// Schedules a new attempt with exponential backoff. Returns false, dropping the message,
+    /// when it failed too many times.
+    fn backoff(&mut self, destination: &str, now: Instant) -> bool {
+        let attempts = {
+            let attempts = self.attempts.entry(String::from(destination)).or_insert(0);
+            *attempts += 1;
+            *attempts
+        };
+        if attempts >= MAX_ATTEMPTS {
+            self.pop(destination);
+            return false;
+        }
+        let delay = cmp::min(2u64.pow(attempts), MAX_BACKOFF_SECS);
+        self.retry_at.insert(
+            String::from(destination),
+            now + Duration::from_secs(delay),
+        );
+        true
+    }
+
+    /// Waits `delay` before the next attempt, as requested by the server.
+    fn retry_after(&mut self, destination: &str, delay: Duration, now: Instant) {
+        self.retry_at.insert(String::from(destination), now + delay);
+    }
+
+    fn apply(&mut self, change: Change<T>) {
+        match change {
+            Change::Push(destination, item) => self.push(&destination, item),
+            Change::Pop(destination) => self.pop(&destination),
+            Change::Migrate(from, to) => self.migrate(&from, &to),
+        }
+    }
+
+    /// Moves the messages of `from` to `to`, and the ones queued for `from` later on.
+    fn migrate(&mut self, from: &str, to: &str) {
+        self.db.migrated.insert(String::from(from), String::from(to));
+        self.retry_at.remove(from);
+        self.attempts.remove(from);
+        if let Some(mut queue) = self.db.queues.remove(from) {
+            let target = self.db.queues.entry(String::from(to)).or_insert_with(
+                VecDeque::new,
+            );
+            target.extend(queue.drain(..));
+        }
+    }
+}
+
+/// The queues and the journal of the changes made since they were saved.
+struct State<T> {
+    queues: Queues<T>,
+    journal: Option<File>,
+    entries: usize,
+}
+
+/// Outbound messages of a transport, saved in data_dir so that nothing is lost while the
+/// network is unreachable or across restarts. Producers only append to it, a single sender
+/// thread takes the messages with next() and reports how the delivery went.
+pub struct Outbox<T> {
+    filename: String,
+    journal_filename: String,
+    state: Mutex<State<T>>,
+    available: Condvar,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Outbox<T> {
+    pub fn new(data_dir: &str, name: &str) -> Outbox<T> {
+        let filename = format!("{}/outbox_{}.json", data_dir, name);
+        let journal_filename = format!("{}/outbox_{}.journal", data_dir, name);
+        let db = match Outbox::read_database(&filename) {
+            Ok(db) => db,
+            Err(e) => {
+                debug!("Starting with an empty outbox: {}", e);
+                Database {
+                    queues: HashMap::new(),
+                    migrated: HashMap::new(),
+                    generation: 0,
+                }
+            }
+        };
+        let mut queues = Queues::new(db);
+        Outbox::replay(&journal_filename, &mut queues);
+        let pending: usize = queues.db.queues.values().map(|q| q.len()).sum();
+        if pending > 0 {
+            info!("Outbox {} has {} pending messages", name, pending);
+        }
+        let outbox = Outbox {
+            filename,
+            journal_filename,
+            state: Mutex::new(State {
+                queues,
+                journal: None,
+                entries: 0,
+            }),
+            available: Condvar::new(),
+        };
+        {
+            let mut state = outbox.state.lock().unwrap();
+            outbox.save(&mut state);
+        }
+        outbox
+    }
+
+    fn read_database(filename: &str) -> Result<Database<T>, Box<Error>> {
+        let file = OpenOptions::new().read(true).open(filename)?;
+        serde_json::from_reader(file).or_else(|e| {
+            Err(From::from(
+                format!("Cannot deserialize file {}: {}", filename, e),
+            ))
+        })
+    }
+
+    /// Applies the changes journaled after the queues were last saved.
+    fn replay(filename: &str, queues: &mut Queues<T>) {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Cannot read file {}: {}", filename, e);
+                    return;
+                }
+            };
+            match serde_json::from_str::<JournalEntry<Change<T>>>(&line) {
+                Ok(entry) => {
+                    if entry.generation == queues.db.generation {
+                        queues.apply(entry.change);
+                    }
+                }
+                // the last line is cut when writing it was interrupted
+                Err(e) => warn!("Skipping an entry of {}: {}", filename, e),
+            }
+        }
+    }
+
+    /// Writes a temporary file renamed over the previous one, so that a crash never leaves
+    /// half of the queues.
+    fn write_database(&self, db: &Database<T>) -> Result<(), Box<Error>> {
+        let temporary = format!("{}.tmp", self.filename);
+        {
+            let mut file = File::create(&temporary)?;
+            serde_json::to_writer(&mut file, db)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, &self.filename)?;
+        Ok(())
+    }
+
+    /// Saves the queues and starts a new, empty journal. The journal entries of the previous
+    /// generation are ignored if a crash leaves them behind.
+    fn save(&self, state: &mut State<T>) {
+        state.queues.db.generation += 1;
+        let truncate = match self.write_database(&state.queues.db) {
+            Ok(()) => true,
+            Err(e) => {
+                // the previous file and journal are still good, keep appending to them
+                error!("Cannot save file {}: {}", self.filename, e);
+                state.queues.db.generation -= 1;
+                false
+            }
+        };
+        if !truncate && state.journal.is_some() {
+            return;
+        }
+        state.entries = 0;
+        let journal = if truncate {
+            File::create(&self.journal_filename)
+        } else {
+            OpenOptions::new().append(true).create(true).open(
+                &self.journal_filename,
+            )
+        };
+        state.journal = match journal {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!("Cannot open file {}: {}", self.journal_filename, e);
+                None
+            }
+        };
+    }
+
+    /// Adds `change`, already applied to the queues, to the journal. The queues are saved
+    /// instead when the journal cannot be written, and once it is long.
+    fn journal(&self, state: &mut State<T>, change: &Change<T>) {
+        let generation = state.queues.db.generation;
+        let written = match state.journal {
+            Some(ref mut journal) => append(journal, &JournalEntry { generation, change }),
+            None => Err(From::from("not open")),
+        };
+        state.entries += 1;
+        match written {
+            Err(e) => {
+                error!("Cannot write file {}: {}", self.journal_filename, e);
+                self.save(state);
+            }
+            Ok(()) if state.entries >= MAX_JOURNAL_ENTRIES => self.save(state),
+            Ok(()) => {}
+        }
+    }
+
+    /// Queues `item` for `destination`, never blocks on the network.
+    pub fn push(&self, destination: &str, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.queues.push(destination, item.clone());
+        self.journal(&mut state, &Change::Push(String::from(destination), item));
+        self.available.notify_one();
+    }
+
+    /// Waits for the next message that can be sent and returns it with its destination. The
+    /// message stays queued until done(), backoff() or retry_after() is called.
+    pub fn next(&self) -> (String, T) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if let Some(ready) = state.queues.ready(now) {
+                return ready;
+            }
+            state = match state.queues.next_retry(now) {
+                Some(wait) => self.available.wait_timeout(state, wait).unwrap().0,
+                None => self.available.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// The first message of `destination` was delivered, or will never be.
+    pub fn done(&self, destination: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.queues.pop(destination);
+        self.journal(&mut state, &Change::Pop(String::from(destination)));
+    }
+
+    /// The first message of `destination` failed, it is retried later.
+    pub fn backoff(&self, destination: &str) {
+        let mut state = self.state.lock().unwrap();
+        if !state.queues.backoff(destination, Instant::now()) {
+            error!(
+                "Giving up on a message for {} after {} attempts",
+                destination,
+                MAX_ATTEMPTS
+            );
+            self.journal(&mut state, &Change::Pop(String::from(destination)));
+        }
+    }
+
+    pub fn retry_after(&self, destination: &str, delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.queues.retry_after(destination, delay, Instant::now());
+    }
+
+    /// Sends everything queued for `from`, now and later, to `to` instead.
+    pub fn migrate(&self, from: &str, to: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.queues.migrate(from, to);
+        self.journal(
+            &mut state,
+            &Change::Migrate(String::from(from), String::from(to)),
+        );
+        self.available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::collections::HashMap;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    use self::tempdir::TempDir;
+
+    use super::{Database, Queues, Outbox};
+
+    fn queues() -> Queues<String> {
+        Queues::new(Database {
+            queues: HashMap::new(),
+            migrated: HashMap::new(),
+            generation: 0,
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut queues = queues();
+        let now = Instant::now();
+        queues.push("a", String::from("a1"));
+        queues.push("a", String::from("a2"));
+        queues.push("b", String::from("b1"));
+        assert_eq!(queues.ready(now), Some((String::from("a"), String::from("a1"))));
+
+        // a waiting destination keeps its order and does not hold back the others
+        assert!(queues.backoff("a", now));
+        assert_eq!(queues.ready(now), Some((String::from("b"), String::from("b1"))));
+        queues.pop("b");
+        assert_eq!(queues.ready(now), None);
+        assert_eq!(queues.next_retry(now), Some(Duration::from_secs(2)));
+        assert!(queues.backoff("a", now));
+        assert_eq!(queues.next_retry(now), Some(Duration::from_secs(4)));
+        assert_eq!(
+            queues.ready(now + Duration::from_secs(4)),
+            Some((String::from("a"), String::from("a1")))
+        );
+
+        // the server can ask for a precise delay
+        queues.retry_after("a", Duration::from_secs(30), now);
+        assert_eq!(queues.next_retry(now), Some(Duration::from_secs(30)));
+
+        for _ in 2..9 {
+            assert!(queues.backoff("a", now));
+        }
+        assert!(!queues.backoff("a", now));
+        assert_eq!(queues.ready(now), Some((String::from("a"), String::from("a2"))));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut queues = queues();
+        let now = Instant::now();
+        queues.push("-1", String::from("one"));
+        queues.migrate("-1", "-100");
+        queues.push("-1", String::from("two"));
+        assert_eq!(queues.ready(now), Some((String::from("-100"), String::from("one"))));
+        queues.pop("-100");
+        assert_eq!(queues.ready(now), Some((String::from("-100"), String::from("two"))));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = TempDir::new("holysee_outbox").unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        {
+            let outbox: Outbox<String> = Outbox::new(data_dir, "test");
+            outbox.push("a", String::from("one"));
+            outbox.push("a", String::from("two"));
+            assert_eq!(outbox.next(), (String::from("a"), String::from("one")));
+            outbox.done("a");
+        }
+        let outbox: Outbox<String> = Outbox::new(data_dir, "test");
+        assert_eq!(outbox.next(), (String::from("a"), String::from("two")));
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = TempDir::new("holysee_outbox").unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let journal = dir.path().join("outbox_test.journal");
+        {
+            let outbox: Outbox<String> = Outbox::new(data_dir, "test");
+            outbox.push("a", String::from("one"));
+            outbox.push("b", String::from("two"));
+            outbox.done("a");
+        }
+        // writing the last change was interrupted
+        OpenOptions::new()
+            .append(true)
+            .open(&journal)
+            .unwrap()
+            .write_all(b"{\"generation\":1,\"change\":{\"Pu")
+            .unwrap();
+
+        let outbox: Outbox<String> = Outbox::new(data_dir, "test");
+        assert_eq!(outbox.next(), (String::from("b"), String::from("two")));
+        // the journal was folded into the saved queues
+        assert_eq!(fs::metadata(&journal).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,135 @@
+extern crate rand;
+
+use std::fs::{self, File};
+use std::error::Error;
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+use self::rand::Rng;
+
+use message::strip_irc_formatting;
+use settings::Paste;
+
+/// Keeps the messages too long for IRC as plain text files in `dir`, served by the http
+/// server under base_url/paste/.
+#[derive(Debug, Clone)]
+pub struct PasteStore {
+    dir: String,
+    base_url: String,
+    max_bytes: usize,
+    expiry: Duration,
+}
+
+impl PasteStore {
+    pub fn new(dir: &str, base_url: &str, settings: &Paste) -> PasteStore {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Cannot create paste directory {}: {}", dir, e);
+        }
+        PasteStore {
+            dir: String::from(dir),
+            base_url: String::from(base_url.trim_right_matches('/')),
+            max_bytes: settings.max_bytes,
+            expiry: Duration::from_secs(settings.expire_days * 24 * 60 * 60),
+        }
+    }
+
+    /// Stores `text` without formatting codes and returns its public link.
+    pub fn store(&self, text: &str) -> Result<String, Box<Error>> {
+        self.expire(SystemTime::now());
+        let text = strip_irc_formatting(text);
+        if text.len() > self.max_bytes {
+            return Err(From::from(format!(
+                "paste of {} bytes exceeds the limit of {}",
+                text.len(),
+                self.max_bytes
+            )));
+        }
+        let name: String = rand::thread_rng().gen_ascii_chars().take(16).collect();
+        let name = format!("{}.txt", name);
+        let mut file = File::create(format!("{}/{}", self.dir, name))?;
+        file.write_all(text.as_bytes())?;
+        info!("Stored paste {} of {} bytes", name, text.len());
+        Ok(format!("{}/paste/{}", self.base_url, name))
+    }
+
+    /// Removes the pastes older than the expiry, as seen at `now`.
+    pub fn expire(&self, now: SystemTime) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Cannot list paste directory {}: {}", self.dir, e);
+                return;
+            }
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let expired = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map_or(false, |age| age > self.expiry);
+            if expired {
+                debug!("Removing expired paste {:?}", entry.path());
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    error!("Cannot remove paste {:?}: {}", entry.path(), e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::time::{Duration, SystemTime};
+
+    use self::tempdir::TempDir;
+
+    use super::PasteStore;
+    use settings::Paste;
+
+    fn settings() -> Paste {
+        Paste {
+            max_lines: 30,
+            max_bytes: 20,
+            expire_days: 1,
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new("holysee_paste").unwrap();
+        let dir_name = dir.path().to_str().unwrap();
+        let store = PasteStore::new(dir_name, "http://example.org/", &settings());
+
+        let url = store.store("\u{2}one\u{2}\ntwo").unwrap();
+        assert!(url.starts_with("http://example.org/paste/"));
+        assert!(url.ends_with(".txt"));
+
+        let name = &url["http://example.org/paste/".len()..];
+        let mut text = String::new();
+        File::open(dir.path().join(name))
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "one\ntwo");
+
+        assert!(store.store("this text is way too long").is_err());
+    }
+
+    #[test]
+    fn test_expire() {
+        let dir = TempDir::new("holysee_paste").unwrap();
+        let dir_name = dir.path().to_str().unwrap();
+        let store = PasteStore::new(dir_name, "http://example.org", &settings());
+        store.store("paste").unwrap();
+
+        store.expire(SystemTime::now());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        store.expire(SystemTime::now() + Duration::from_secs(2 * 24 * 60 * 60));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use message::TransportType;
+use settings::Bridge;
+
+/// One side of a bridge: a channel or chat on a given transport.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub transport: TransportType,
+    pub channel: String,
+}
+
+impl Endpoint {
+    pub fn new(transport: TransportType, channel: &str) -> Endpoint {
+        // telegram chat ids may be written with leading zeros in the config file
+        let channel = match channel.parse::<i64>() {
+            Ok(id) => id.to_string(),
+            Err(_) => channel.to_owned(),
+        };
+        Endpoint { transport, channel }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub to: Endpoint,
+    /// Tag of the source endpoint, set only when more than one source feeds `to`.
+    pub tag: Option<String>,
+}
+
+/// Many-to-many routing table built from the [[bridges]] section: every endpoint of a bridge
+/// relays to all the other endpoints of the same bridge.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: HashMap<Endpoint, Vec<Route>>,
+}
+
+impl RoutingTable {
+    pub fn new(bridges: &[Bridge]) -> RoutingTable {
+        let mut links: Vec<(Endpoint, Endpoint, Option<String>)> = vec![];
+        for bridge in bridges {
+            let mut endpoints: Vec<(Endpoint, Option<String>)> = vec![];
+            for endpoint in &bridge.endpoints {
+                match TransportType::from_id(&endpoint.transport) {
+                    Some(t) => endpoints.push((Endpoint::new(t, &endpoint.channel), endpoint.tag.clone())),
+                    None => error!("Unknown transport {} in bridge, skipping endpoint", endpoint.transport),
+                }
+            }
+            for &(ref from, ref tag) in &endpoints {
+                for &(ref to, _) in &endpoints {
+                    if from == to || links.iter().any(|&(ref f, ref t, _)| f == from && t == to) {
+                        continue;
+                    }
+                    links.push((from.clone(), to.clone(), tag.clone()));
+                }
+            }
+        }
+
+        let mut routes: HashMap<Endpoint, Vec<Route>> = HashMap::new();
+        for &(ref from, ref to, ref tag) in &links {
+            let sources = links.iter().filter(|&&(_, ref t, _)| t == to).count();
+            routes.entry(from.clone()).or_insert_with(Vec::new).push(Route {
+                to: to.clone(),
+                tag: if sources > 1 { tag.clone() } else { None },
+            });
+        }
+        debug!("Routing table: {:#?}", routes);
+        RoutingTable { routes }
+    }
+
+    pub fn routes(&self, from: &Endpoint) -> &[Route] {
+        match self.routes.get(from) {
+            Some(r) => r,
+            None => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RoutingTable, Endpoint};
+    use message::TransportType;
+    use settings::{Bridge, BridgeEndpoint};
+
+    fn endpoint(transport: &str, channel: &str, tag: Option<&str>) -> BridgeEndpoint {
+        BridgeEndpoint {
+            transport: String::from(transport),
+            channel: String::from(channel),
+            tag: tag.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_single_bridge() {
+        let bridges = vec![
+            Bridge {
+                endpoints: vec![
+                    endpoint("irc", "#somechan", Some("irc")),
+                    endpoint("telegram", "012345", Some("tg")),
+                ],
+            },
+        ];
+        let routing = RoutingTable::new(&bridges);
+
+        let routes = routing.routes(&Endpoint::new(TransportType::IRC, "#somechan"));
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].to, Endpoint::new(TransportType::Telegram, "12345"));
+        assert_eq!(routes[0].tag, None);
+
+        let routes = routing.routes(&Endpoint::new(TransportType::Telegram, "12345"));
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].to, Endpoint::new(TransportType::IRC, "#somechan"));
+
+        assert!(routing.routes(&Endpoint::new(TransportType::IRC, "#otherchan")).is_empty());
+    }
+
+    #[test]
+    fn test_tags_with_several_sources() {
+        let bridges = vec![
+            Bridge {
+                endpoints: vec![
+                    endpoint("irc", "#first", Some("first")),
+                    endpoint("irc", "#second", None),
+                    endpoint("telegram", "-100123", Some("tg")),
+                ],
+            },
+        ];
+        let routing = RoutingTable::new(&bridges);
+
+        let routes = routing.routes(&Endpoint::new(TransportType::IRC, "#first"));
+        assert_eq!(routes.len(), 2);
+        assert!(routes.iter().all(|r| r.tag == Some(String::from("first"))));
+
+        let routes = routing.routes(&Endpoint::new(TransportType::IRC, "#second"));
+        assert_eq!(routes.len(), 2);
+        assert!(routes.iter().all(|r| r.tag == None));
+    }
+
+    #[test]
+    fn test_unknown_transport() {
+        let bridges = vec![
+            Bridge {
+                endpoints: vec![
+                    endpoint("irc", "#somechan", None),
+                    endpoint("carrier_pigeon", "loft", None),
+                ],
+            },
+        ];
+        let routing = RoutingTable::new(&bridges);
+        assert!(routing.routes(&Endpoint::new(TransportType::IRC, "#somechan")).is_empty());
+    }
+}
@@ -1,6 +1,15 @@
-use config::{Config, ConfigError, File};
+use std::collections::HashMap;
+use std::env;
+use config::{Config, ConfigError, File, Value};
+use serde::de::{Deserialize, DeserializeOwned, Deserializer, Error};
 
-#[derive(Debug, Deserialize)]
+use ircauth::Mechanism;
+use message::TransportType;
+
+// environment variables starting with this override the configuration file
+const ENV_PREFIX: &str = "HOLYSEE_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Irc {
     pub nickname: String,
     pub username: String,
@@ -11,48 +20,582 @@ pub struct Irc {
     pub channel: String,
     pub ssl: bool,
     pub ssl_verify: bool,
+    /// PEM or DER certificates trusted besides the system ones, for a private CA.
+    pub ssl_ca_file: Option<String>,
+    /// PKCS#12 bundle with the client certificate and its key, for CertFP.
+    pub ssl_client_cert: Option<String>,
+    pub ssl_client_cert_password: Option<String>,
+    /// The server certificate must be this one (PEM or DER), whoever signed it.
+    pub ssl_pinned_cert: Option<String>,
     pub allow_receive: bool,
+    #[serde(default)]
+    pub announce: IrcAnnounce,
+    #[serde(default)]
+    pub flood: IrcFlood,
+    pub sasl: Option<IrcSasl>,
+}
+
+/// SASL authentication while connecting, NickServ is used when it is not available.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IrcSasl {
+    /// "plain", with the account and irc.password, or "external" with the client certificate.
+    pub mechanism: String,
+    /// Account name, the nickname when missing.
+    pub account: Option<String>,
+}
+
+/// Which IRC presence changes are announced on the other transports.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct IrcAnnounce {
+    pub join: bool,
+    pub part: bool,
+    pub quit: bool,
+    pub kick: bool,
+    pub nick: bool,
+    /// Announcements sent in a minute at most, 0 to disable the limit.
+    pub per_minute: usize,
+    /// Seconds netsplit quits and rejoins are collected for before being summarized.
+    pub netsplit_window_secs: u64,
+}
+
+impl Default for IrcAnnounce {
+    fn default() -> IrcAnnounce {
+        IrcAnnounce {
+            join: false,
+            part: false,
+            quit: false,
+            kick: false,
+            nick: false,
+            per_minute: 10,
+            netsplit_window_secs: 5,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Output budget on the IRC network: `burst` lines at once, then one every `refill_ms`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct IrcFlood {
+    pub burst: usize,
+    pub refill_ms: u64,
+}
+
+impl Default for IrcFlood {
+    fn default() -> IrcFlood {
+        // within the excess flood limits of freenode, oftc and quakenet
+        IrcFlood {
+            burst: 4,
+            refill_ms: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Telegram {
     pub token: String,
+    /// Base url of a self-hosted Bot API server, the official one when missing.
+    pub api_url: Option<String>,
+    /// Updates older than this are skipped, 1 hour when missing, 0 keeps them all.
+    pub max_update_age_secs: Option<u64>,
     pub chat_id: i64,
     pub allow_receive: bool,
+    #[serde(default)]
+    pub announce: TelegramAnnounce,
+    #[serde(default)]
+    pub mode: TelegramMode,
+    pub webhook: Option<TelegramWebhook>,
+}
+
+/// How the updates are received from Telegram.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TelegramMode {
+    /// Long polling with getUpdates.
+    Polling,
+    /// Telegram posts them to [telegram.webhook].
+    Webhook,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for TelegramMode {
+    fn default() -> TelegramMode {
+        TelegramMode::Polling
+    }
+}
+
+// read from a string, the config crate cannot deserialize enums
+impl<'de> Deserialize<'de> for TelegramMode {
+    fn deserialize<D>(deserializer: D) -> Result<TelegramMode, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mode = String::deserialize(deserializer)?;
+        match mode.as_str() {
+            "polling" => Ok(TelegramMode::Polling),
+            "webhook" => Ok(TelegramMode::Webhook),
+            _ => Err(D::Error::custom(
+                format!("unknown mode {}, use polling or webhook", mode),
+            )),
+        }
+    }
+}
+
+/// The embedded endpoint receiving the updates in webhook mode.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TelegramWebhook {
+    /// Public https url registered with Telegram, its path is the one served.
+    pub url: String,
+    /// Address of the listener, not the one of [http].
+    pub listen: String,
+    /// Checked against the X-Telegram-Bot-Api-Secret-Token header of every request.
+    pub secret: Option<String>,
+    /// PKCS#12 certificate and key, to serve https without a reverse proxy.
+    pub tls_identity: Option<String>,
+    pub tls_identity_password: Option<String>,
+}
+
+/// Which Telegram group changes are announced on the other transports.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TelegramAnnounce {
+    pub join: bool,
+    pub leave: bool,
+    pub title: bool,
+    pub photo: bool,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Matrix {
+    pub homeserver: String,
+    pub access_token: String,
+    pub user_id: String,
+    pub room_id: String,
+    pub allow_receive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Xmpp {
+    pub jid: String,
+    pub password: String,
+    pub host: String,
+    pub port: u16,
+    pub room: String,
+    pub nickname: String,
+    pub ssl: bool,
+    pub allow_receive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Http {
+    pub listen: String,
+    pub base_url: String,
+}
+
+/// Limits of the paste store, used for the messages too long to be sent to IRC.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Paste {
+    /// Messages longer than this many IRC lines are pasted.
+    pub max_lines: usize,
+    /// Bigger messages are not pasted but dropped.
+    pub max_bytes: usize,
+    /// Pastes are removed after this many days.
+    pub expire_days: u64,
+}
+
+impl Default for Paste {
+    fn default() -> Paste {
+        Paste {
+            max_lines: 30,
+            max_bytes: 512 * 1024,
+            expire_days: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Commands {
     pub data_dir: String,
     pub enabled: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct NickEntry {
     pub telegram: String,
     pub irc: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BridgeEndpoint {
+    pub transport: String,
+    pub channel: String,
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Bridge {
+    pub endpoints: Vec<BridgeEndpoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Settings {
     pub debug: bool,
     pub command_prefix: String,
     pub irc: Irc,
     pub telegram: Telegram,
+    pub matrix: Option<Matrix>,
+    pub xmpp: Option<Xmpp>,
+    pub http: Option<Http>,
+    #[serde(default)]
+    pub paste: Paste,
     pub commands: Commands,
     pub nicknames: Vec<NickEntry>,
+    #[serde(default)]
+    pub bridges: Vec<Bridge>,
+}
+
+/// The configuration key overridden by an environment variable: HOLYSEE_TELEGRAM__TOKEN sets
+/// telegram.token. Sections are separated by two underscores, as keys have single ones.
+fn env_key(name: &str) -> Option<String> {
+    if !name.starts_with(ENV_PREFIX) || name.len() == ENV_PREFIX.len() {
+        return None;
+    }
+    Some(name[ENV_PREFIX.len()..].to_lowercase().replace("__", "."))
+}
+
+/// Reads `path`, with or without its extension, and applies the environment overrides.
+fn load(path: &str) -> Result<Config, ConfigError> {
+    debug!("Loading configuration from {}", path);
+    let mut s = Config::new();
+    s.merge(File::with_name(path))?;
+    for (name, value) in env::vars() {
+        if let Some(key) = env_key(&name) {
+            debug!("Configuration key {} set from {}", key, name);
+            s.set(&key, value)?;
+        }
+    }
+    Ok(s)
+}
+
+/// Collects the problems of a configuration instead of stopping at the first one.
+struct Checker<'a> {
+    config: &'a Config,
+    problems: Vec<String>,
+}
+
+impl<'a> Checker<'a> {
+    fn problem(&mut self, key: &str, problem: &str) {
+        self.problems.push(format!("{}: {}", key, problem));
+    }
+
+    /// The value of `key` if it is present and has the right type.
+    fn key<T: DeserializeOwned>(&mut self, key: &str, required: bool) -> Option<T> {
+        match self.config.get::<T>(key) {
+            Ok(value) => Some(value),
+            Err(ConfigError::NotFound(_)) => {
+                if required {
+                    self.problem(key, "missing");
+                }
+                None
+            }
+            Err(e) => {
+                self.problem(key, &e.to_string());
+                None
+            }
+        }
+    }
+
+    fn keys<T: DeserializeOwned>(&mut self, section: &str, keys: &[&str], required: bool) {
+        for key in keys {
+            self.key::<T>(&format!("{}.{}", section, key), required);
+        }
+    }
+
+    /// True if the section is present, the keys of a missing optional section are not checked.
+    fn section(&mut self, section: &str, required: bool) -> bool {
+        match self.config.get::<Value>(section) {
+            Ok(_) => true,
+            Err(ConfigError::NotFound(_)) => {
+                if required {
+                    self.problem(section, "missing section");
+                }
+                false
+            }
+            Err(e) => {
+                self.problem(section, &e.to_string());
+                false
+            }
+        }
+    }
+
+    fn check_irc(&mut self) {
+        if !self.section("irc", true) {
+            return;
+        }
+        let strings = [
+            "nickname",
+            "username",
+            "real_name",
+            "password",
+            "host",
+            "channel",
+        ];
+        self.keys::<String>("irc", &strings, true);
+        self.key::<u16>("irc.port", true);
+        self.keys::<bool>("irc", &["ssl", "ssl_verify", "allow_receive"], true);
+        let files = [
+            "ssl_ca_file",
+            "ssl_client_cert",
+            "ssl_client_cert_password",
+            "ssl_pinned_cert",
+        ];
+        self.keys::<String>("irc", &files, false);
+        if self.section("irc.announce", false) {
+            let events = ["join", "part", "quit", "kick", "nick"];
+            self.keys::<bool>("irc.announce", &events, false);
+            self.key::<usize>("irc.announce.per_minute", false);
+            self.key::<u64>("irc.announce.netsplit_window_secs", false);
+        }
+        if self.section("irc.flood", false) {
+            self.key::<usize>("irc.flood.burst", false);
+            self.key::<u64>("irc.flood.refill_ms", false);
+        }
+        if self.section("irc.sasl", false) {
+            if let Some(mechanism) = self.key::<String>("irc.sasl.mechanism", true) {
+                if Mechanism::from_name(&mechanism).is_none() {
+                    self.problem("irc.sasl.mechanism", "must be plain or external");
+                }
+            }
+            self.key::<String>("irc.sasl.account", false);
+        }
+    }
+
+    fn check_telegram(&mut self) {
+        if !self.section("telegram", true) {
+            return;
+        }
+        self.key::<String>("telegram.token", true);
+        self.key::<String>("telegram.api_url", false);
+        self.key::<u64>("telegram.max_update_age_secs", false);
+        self.key::<i64>("telegram.chat_id", true);
+        self.key::<bool>("telegram.allow_receive", true);
+        if self.section("telegram.announce", false) {
+            let events = ["join", "leave", "title", "photo", "pinned"];
+            self.keys::<bool>("telegram.announce", &events, false);
+        }
+        let mode = self.key::<TelegramMode>("telegram.mode", false);
+        if self.section("telegram.webhook", mode == Some(TelegramMode::Webhook)) {
+            self.keys::<String>("telegram.webhook", &["url", "listen"], true);
+            let optional = ["secret", "tls_identity", "tls_identity_password"];
+            self.keys::<String>("telegram.webhook", &optional, false);
+        }
+    }
+
+    fn check_optional_transports(&mut self) {
+        if self.section("matrix", false) {
+            let strings = ["homeserver", "access_token", "user_id", "room_id"];
+            self.keys::<String>("matrix", &strings, true);
+            self.key::<bool>("matrix.allow_receive", true);
+        }
+        if self.section("xmpp", false) {
+            let strings = ["jid", "password", "host", "room", "nickname"];
+            self.keys::<String>("xmpp", &strings, true);
+            self.key::<u16>("xmpp.port", true);
+            self.keys::<bool>("xmpp", &["ssl", "allow_receive"], true);
+        }
+        if self.section("http", false) {
+            self.keys::<String>("http", &["listen", "base_url"], true);
+        }
+        if self.section("paste", false) {
+            self.keys::<usize>("paste", &["max_lines", "max_bytes"], false);
+            self.key::<u64>("paste.expire_days", false);
+        }
+    }
+
+    fn check_lists(&mut self) {
+        if let Some(nicknames) = self.key::<Vec<Value>>("nicknames", true) {
+            for i in 0..nicknames.len() {
+                self.keys::<String>(&format!("nicknames[{}]", i), &["irc", "telegram"], true);
+            }
+        }
+        if let Some(bridges) = self.key::<Vec<Value>>("bridges", false) {
+            for i in 0..bridges.len() {
+                let key = format!("bridges[{}].endpoints", i);
+                let endpoints = match self.key::<Vec<Value>>(&key, true) {
+                    Some(endpoints) => endpoints,
+                    None => continue,
+                };
+                for j in 0..endpoints.len() {
+                    let endpoint = format!("{}[{}]", key, j);
+                    let transport_key = format!("{}.transport", endpoint);
+                    if let Some(transport) = self.key::<String>(&transport_key, true) {
+                        if TransportType::from_id(&transport).is_none() {
+                            self.problem(&transport_key, "unknown transport");
+                        }
+                    }
+                    self.key::<String>(&format!("{}.channel", endpoint), true);
+                    self.key::<String>(&format!("{}.tag", endpoint), false);
+                }
+            }
+        }
+    }
+
+    fn check(mut self) -> Vec<String> {
+        self.key::<bool>("debug", true);
+        self.key::<String>("command_prefix", true);
+        self.check_irc();
+        self.check_telegram();
+        self.check_optional_transports();
+        if self.section("commands", true) {
+            self.key::<String>("commands.data_dir", true);
+            self.key::<Vec<String>>("commands.enabled", true);
+        }
+        self.check_lists();
+        // anything the checks above do not know about
+        if self.problems.is_empty() {
+            if let Err(e) = self.config.deserialize::<Settings>() {
+                self.problems.push(e.to_string());
+            }
+        }
+        self.problems
+    }
 }
 
 impl Settings {
-    pub fn new(prod: bool) -> Result<Self, ConfigError> {
-        debug!("Loading configuration from config/local.toml");
-        let mut s = Config::new();
-        if prod {
-            s.merge(File::with_name("config/local"));
-        } else {
-            s.merge(File::with_name("config/example"));
+    /// Loads the configuration file at `path`, see load().
+    pub fn new(path: &str) -> Result<Self, ConfigError> {
+        load(path)?.deserialize()
+    }
+
+    /// Every problem of the configuration at `path`, with its key.
+    pub fn check(path: &str) -> Vec<String> {
+        match load(path) {
+            Ok(config) => {
+                Checker {
+                    config: &config,
+                    problems: vec![],
+                }.check()
+            }
+            Err(e) => vec![e.to_string()],
+        }
+    }
+
+    pub fn allow_receive(&self) -> HashMap<TransportType, bool> {
+        let mut allow_receive = HashMap::new();
+        allow_receive.insert(TransportType::IRC, self.irc.allow_receive);
+        allow_receive.insert(TransportType::Telegram, self.telegram.allow_receive);
+        if let Some(ref matrix) = self.matrix {
+            allow_receive.insert(TransportType::Matrix, matrix.allow_receive);
+        }
+        if let Some(ref xmpp) = self.xmpp {
+            allow_receive.insert(TransportType::XMPP, xmpp.allow_receive);
+        }
+        allow_receive
+    }
+
+    /// Returns the configured bridges. Without a [[bridges]] section irc.channel,
+    /// telegram.chat_id, matrix.room_id and xmpp.room are bridged together, as in the old
+    /// single channel setup.
+    pub fn bridges(&self) -> Vec<Bridge> {
+        if !self.bridges.is_empty() {
+            return self.bridges.clone();
+        }
+        let mut endpoints = vec![
+            BridgeEndpoint {
+                transport: String::from(TransportType::IRC.id()),
+                channel: self.irc.channel.clone(),
+                tag: None,
+            },
+            BridgeEndpoint {
+                transport: String::from(TransportType::Telegram.id()),
+                channel: self.telegram.chat_id.to_string(),
+                tag: None,
+            },
+        ];
+        if let Some(ref matrix) = self.matrix {
+            endpoints.push(BridgeEndpoint {
+                transport: String::from(TransportType::Matrix.id()),
+                channel: matrix.room_id.clone(),
+                tag: None,
+            });
+        }
+        if let Some(ref xmpp) = self.xmpp {
+            endpoints.push(BridgeEndpoint {
+                transport: String::from(TransportType::XMPP.id()),
+                channel: xmpp.room.clone(),
+                tag: None,
+            });
         }
-        s.deserialize()
+        vec![Bridge { endpoints }]
+    }
+
+    /// All the channels a transport takes part in, across every bridge.
+    pub fn channels(&self, transport: &TransportType) -> Vec<String> {
+        let mut channels: Vec<String> = vec![];
+        for bridge in self.bridges() {
+            for endpoint in bridge.endpoints {
+                if endpoint.transport == transport.id() && !channels.contains(&endpoint.channel) {
+                    channels.push(endpoint.channel);
+                }
+            }
+        }
+        channels
+    }
+
+    /// Where the media relayed from Telegram are stored and served from.
+    pub fn media_dir(&self) -> String {
+        format!("{}/media", self.commands.data_dir)
+    }
+
+    /// Where the messages too long for IRC are stored and served from.
+    pub fn paste_dir(&self) -> String {
+        format!("{}/pastes", self.commands.data_dir)
+    }
+
+    /// The sections changed in `other` that are only read at startup, by the transports and
+    /// the http server. The command prefix, nicknames, enabled commands and allow_receive
+    /// flags are reloaded by the commands instead.
+    pub fn restart_required(&self, other: &Settings) -> Vec<&'static str> {
+        let mut other = other.clone();
+        other.irc.allow_receive = self.irc.allow_receive;
+        other.telegram.allow_receive = self.telegram.allow_receive;
+        if let (&Some(ref matrix), &mut Some(ref mut other_matrix)) =
+            (&self.matrix, &mut other.matrix)
+        {
+            other_matrix.allow_receive = matrix.allow_receive;
+        }
+        if let (&Some(ref xmpp), &mut Some(ref mut other_xmpp)) = (&self.xmpp, &mut other.xmpp) {
+            other_xmpp.allow_receive = xmpp.allow_receive;
+        }
+        let mut changed = vec![];
+        if self.debug != other.debug {
+            changed.push("debug");
+        }
+        if self.irc != other.irc {
+            changed.push("irc");
+        }
+        if self.telegram != other.telegram {
+            changed.push("telegram");
+        }
+        if self.matrix != other.matrix {
+            changed.push("matrix");
+        }
+        if self.xmpp != other.xmpp {
+            changed.push("xmpp");
+        }
+        if self.http != other.http {
+            changed.push("http");
+        }
+        if self.paste != other.paste {
+            changed.push("paste");
+        }
+        if self.commands.data_dir != other.commands.data_dir {
+            changed.push("commands.data_dir");
+        }
+        if self.bridges() != other.bridges() {
+            changed.push("bridges");
+        }
+        changed
     }
 
     pub fn enable_default_commands(settings: &mut Settings) {
@@ -60,3 +603,102 @@ impl Settings {
         settings.commands.enabled.push(String::from("usage"));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::File;
+    use std::io::Write;
+
+    use self::tempdir::TempDir;
+
+    use super::{Settings, env_key};
+
+    #[test]
+    fn test_env_key() {
+        assert_eq!(env_key("HOLYSEE_TELEGRAM__TOKEN"), Some(String::from("telegram.token")));
+        assert_eq!(
+            env_key("HOLYSEE_IRC__SSL_VERIFY"),
+            Some(String::from("irc.ssl_verify"))
+        );
+        assert_eq!(env_key("HOLYSEE_"), None);
+        assert_eq!(env_key("HOME"), None);
+    }
+
+    #[test]
+    fn test_check_example() {
+        assert_eq!(Settings::check("config/example"), Vec::<String>::new());
+        assert!(Settings::new("config/example").is_ok());
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let settings = Settings::new("config/example").unwrap();
+        let mut other = settings.clone();
+        other.command_prefix = String::from("?");
+        other.nicknames.clear();
+        other.commands.enabled.clear();
+        other.irc.allow_receive = false;
+        other.telegram.allow_receive = false;
+        assert!(settings.restart_required(&other).is_empty());
+
+        other.irc.port = 6667;
+        other.telegram.token = String::from("another");
+        other.commands.data_dir = String::from("/var/lib/holysee");
+        other.bridges.clear();
+        assert_eq!(
+            settings.restart_required(&other),
+            vec!["irc", "telegram", "commands.data_dir", "bridges"]
+        );
+    }
+
+    #[test]
+    fn test_check_problems() {
+        let dir = TempDir::new("holysee_settings").unwrap();
+        File::create(dir.path().join("broken.toml"))
+            .unwrap()
+            .write_all(
+                b"debug = \"maybe\"
+command_prefix = \"!\"
+nicknames = [{ irc = \"user\" }]
+
+[irc]
+username = \"user\"
+real_name = \"holysee\"
+password = \"\"
+host = \"irc.example.org\"
+port = \"ircs\"
+channel = \"#example\"
+ssl = true
+ssl_verify = true
+allow_receive = true
+
+[telegram]
+token = \"token\"
+chat_id = \"group\"
+allow_receive = true
+mode = \"webhook\"
+",
+            )
+            .unwrap();
+        let path = dir.path().join("broken");
+        let problems = Settings::check(path.to_str().unwrap());
+        let keys: Vec<&str> = problems
+            .iter()
+            .map(|p| p.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "debug",
+                "irc.nickname",
+                "irc.port",
+                "telegram.chat_id",
+                "telegram.webhook",
+                "commands",
+                "nicknames[0].telegram",
+            ]
+        );
+    }
+}
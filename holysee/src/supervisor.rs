@@ -0,0 +1,223 @@
+use std::cmp;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use chan::Sender;
+
+use message::{Message, MessageType, TransportType, DestinationType};
+
+const MIN_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 300;
+// a single dropped connection that comes back at once is not worth a notice
+const FAILURES_BEFORE_NOTICE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkState {
+    Connecting,
+    Up,
+    Down,
+}
+
+/// Delays between reconnection attempts: doubled after every failure, up to a limit.
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff { next: min, max }
+    }
+
+    pub fn next(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = cmp::min(self.next * 2, self.max);
+        delay
+    }
+}
+
+fn label(transport: &TransportType) -> &'static str {
+    match *transport {
+        TransportType::IRC => "IRC",
+        TransportType::Telegram => "Telegram",
+        TransportType::Matrix => "Matrix",
+        TransportType::XMPP => "XMPP",
+        _ => transport.id(),
+    }
+}
+
+#[derive(Debug)]
+struct LinkStatus {
+    state: LinkState,
+    failures: u32,
+    announced_down: bool,
+}
+
+/// Connection state of a transport. Outages are announced in the channels of the transport,
+/// so the relay tells the other side why the messages stopped.
+#[derive(Clone)]
+pub struct Link {
+    transport: TransportType,
+    channels: Vec<String>,
+    to_main_queue: Sender<Message>,
+    status: Arc<Mutex<LinkStatus>>,
+}
+
+impl Link {
+    pub fn new(
+        transport: TransportType,
+        channels: Vec<String>,
+        to_main_queue: Sender<Message>,
+    ) -> Link {
+        Link {
+            transport,
+            channels,
+            to_main_queue,
+            status: Arc::new(Mutex::new(LinkStatus {
+                state: LinkState::Connecting,
+                failures: 0,
+                announced_down: false,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.status.lock().unwrap().state
+    }
+
+    fn notice(&self, text: &str) {
+        for channel in &self.channels {
+            self.to_main_queue.send(
+                Message::new(
+                    self.transport,
+                    String::from(text),
+                    String::from(self.transport.id()),
+                    DestinationType::Channel(channel.clone()),
+                    true,
+                ).with_type(MessageType::Event),
+            );
+        }
+    }
+
+    fn connecting(&self) {
+        self.status.lock().unwrap().state = LinkState::Connecting;
+    }
+
+    /// The transport is connected and in its channels.
+    pub fn up(&self) {
+        let announce = {
+            let mut status = self.status.lock().unwrap();
+            if status.state == LinkState::Up {
+                return;
+            }
+            status.state = LinkState::Up;
+            status.failures = 0;
+            let announce = status.announced_down;
+            status.announced_down = false;
+            announce
+        };
+        info!("{} link up", label(&self.transport));
+        if announce {
+            self.notice(&format!("{} link restored", label(&self.transport)));
+        }
+    }
+
+    fn down(&self, reason: &str) {
+        let announce = {
+            let mut status = self.status.lock().unwrap();
+            status.state = LinkState::Down;
+            status.failures += 1;
+            let announce = !status.announced_down && status.failures >= FAILURES_BEFORE_NOTICE;
+            if announce {
+                status.announced_down = true;
+            }
+            announce
+        };
+        warn!("{} link down: {}", label(&self.transport), reason);
+        if announce {
+            self.notice(&format!("{} link down ({})", label(&self.transport), reason));
+        }
+    }
+
+    /// Runs `session` again and again, waiting longer after every failure. A session connects,
+    /// joins the channels, calls up() and returns when the connection is lost.
+    pub fn supervise<F>(&self, mut session: F)
+    where
+        F: FnMut() -> Result<(), Box<Error>>,
+    {
+        let min = Duration::from_secs(MIN_BACKOFF_SECS);
+        let max = Duration::from_secs(MAX_BACKOFF_SECS);
+        let mut backoff = Backoff::new(min, max);
+        loop {
+            self.connecting();
+            let reason = match session() {
+                Ok(_) => String::from("connection closed"),
+                Err(e) => e.to_string(),
+            };
+            // a session that got connected starts the backoff over
+            if self.state() == LinkState::Up {
+                backoff = Backoff::new(min, max);
+            }
+            self.down(&reason);
+            let delay = backoff.next();
+            info!(
+                "Reconnecting {} in {} seconds",
+                label(&self.transport),
+                delay.as_secs()
+            );
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chan;
+
+    use super::{Backoff, Link, LinkState};
+    use message::{MessageType, TransportType, DestinationType};
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+        assert_eq!(backoff.next(), Duration::from_secs(2));
+        assert_eq!(backoff.next(), Duration::from_secs(4));
+        assert_eq!(backoff.next(), Duration::from_secs(5));
+        assert_eq!(backoff.next(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_notices() {
+        let (to_main, from_link) = chan::async();
+        let link = Link::new(TransportType::IRC, vec![String::from("#chan")], to_main);
+        assert_eq!(link.state(), LinkState::Connecting);
+        link.up();
+        assert_eq!(link.state(), LinkState::Up);
+
+        // the first failure could be a blip, the second one is announced
+        link.down("ping timeout");
+        link.down("connection refused");
+        assert_eq!(link.state(), LinkState::Down);
+        link.down("connection refused");
+        link.up();
+
+        let notice = from_link.recv().unwrap();
+        assert_eq!(notice.text, "IRC link down (connection refused)");
+        assert_eq!(notice.message_type, MessageType::Event);
+        match notice.to {
+            DestinationType::Channel(ref c) => assert_eq!(c, "#chan"),
+            _ => panic!("notice not sent to the channel"),
+        }
+        assert_eq!(from_link.recv().unwrap().text, "IRC link restored");
+
+        // a blip that comes back at once is not announced
+        link.down("ping timeout");
+        link.up();
+        drop(link);
+        assert!(from_link.recv().is_none());
+    }
+}
@@ -1,32 +1,312 @@
 pub mod client {
     extern crate futures;
+    extern crate native_tls;
     extern crate telegram_bot;
     extern crate tokio_core;
     extern crate chan;
+    extern crate rand;
+    extern crate regex;
+    extern crate reqwest;
+    extern crate serde_json;
 
-    use std::thread;
+    use std::{fs, io, thread};
+    use std::collections::{HashMap, VecDeque};
+    use std::error::Error;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use chan::{Sender, Receiver};
 
-    use self::futures::Stream;
-    use self::telegram_bot::Api;
-    use self::telegram_bot::types::{ChatId, MessageKind, SendMessage, UpdateKind, Chat};
-    use self::tokio_core::reactor::Core;
+    use self::futures::{Future, Stream};
+    use self::futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use self::native_tls::{Pkcs12, TlsAcceptor};
+    use self::rand::Rng;
+    use self::regex::Regex;
+    use self::telegram_bot::{Api, ErrorKind as TelegramErrorKind};
+    use self::telegram_bot::types::{ChatId, MessageKind, SendMessage, Chat, User};
+    use self::telegram_bot::types::{File as TelegramFile, FileRef, GetFile, Location};
+    use self::telegram_bot::types::{Message as TelegramMessage, MessageId, GetMe, UserId};
+    use self::telegram_bot::types::{ParseMode, MessageEntity, MessageEntityKind};
+    use self::telegram_bot::types::{Update, UpdateKind, SetWebhook, DeleteWebhook};
+    use self::telegram_bot::types::{normalize_base_url, TELEGRAM_URL};
+    use self::tokio_core::reactor::{Core, Handle};
 
-    use settings::Settings;
-    use message::{Message, TransportType, DestinationType};
+    use settings::{Settings, TelegramAnnounce, TelegramMode, TelegramWebhook};
+    use httpserver::{HttpServer, Request, Response};
+    use message::{Message, MessageType, TransportType, DestinationType};
+    use message::{irc_to_html, spans_to_irc, strip_irc_formatting, Span, SpanStyle};
+    use outbox::Outbox;
+    use supervisor::{Link, LinkState};
+    use transport::{Transport, Capabilities};
 
-    fn main_to_telegram_loop(from_main_queue: &Receiver<Message>, token: &str, chat_id: i64) {
-        let mut core = Core::new().unwrap();
-        let api = Api::configure(token).build(core.handle());
-        let chat = ChatId::new(chat_id);
+    // updates older than this are not relayed, unless configured otherwise
+    const DEFAULT_MAX_UPDATE_AGE_SECS: u64 = 3600;
+
+    /// Latest Telegram message id of every nick, by chat: both the messages of the Telegram
+    /// users and the ones relayed by the bot for the other transports. Saved in data_dir, so
+    /// that replies keep working across restarts.
+    #[derive(Debug)]
+    struct MessageIds {
+        filename: String,
+        ids: HashMap<String, HashMap<String, i64>>,
+    }
+
+    impl MessageIds {
+        fn new(data_dir: &str) -> MessageIds {
+            let filename = format!("{}/telegram_messages.json", data_dir);
+            let ids = match MessageIds::read_database(&filename) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    error!("Error reading database: {}", e);
+                    HashMap::new()
+                }
+            };
+            MessageIds { filename, ids }
+        }
+
+        fn read_database(filename: &str) -> Result<HashMap<String, HashMap<String, i64>>, Box<Error>> {
+            let file = OpenOptions::new().read(true).open(filename)?;
+            serde_json::from_reader(file).or_else(|e| {
+                Err(From::from(
+                    format!("Cannot deserialize file {}: {}", filename, e),
+                ))
+            })
+        }
+
+        fn write_database(&self) -> bool {
+            match OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.filename) {
+                Ok(file) => {
+                    if let Err(e) = serde_json::to_writer(file, &self.ids) {
+                        error!("Cannot serialize file {}: {}", self.filename, e);
+                        return false;
+                    };
+                }
+                Err(e) => {
+                    error!("Cannot open file {}: {}", self.filename, e);
+                    return false;
+                }
+            };
+            true
+        }
+
+        fn get(&self, chat: &str, nick: &str) -> Option<i64> {
+            self.ids.get(chat).and_then(|nicks| nicks.get(nick)).cloned()
+        }
+
+        fn update(&mut self, chat: String, nick: String, id: i64) {
+            self.ids.entry(chat).or_insert_with(HashMap::new).insert(nick, id);
+            self.write_database();
+        }
+    }
+
+    /// Id of the last Telegram update handled, saved in data_dir to resume after it on restart.
+    #[derive(Debug)]
+    struct UpdateOffset {
+        filename: String,
+        last_update: i64,
+    }
+
+    impl UpdateOffset {
+        fn new(data_dir: &str) -> UpdateOffset {
+            let filename = format!("{}/telegram_offset.json", data_dir);
+            let last_update = match UpdateOffset::read_database(&filename) {
+                Ok(last_update) => last_update,
+                Err(e) => {
+                    debug!("Starting from the unconfirmed Telegram updates: {}", e);
+                    0
+                }
+            };
+            UpdateOffset {
+                filename,
+                last_update,
+            }
+        }
+
+        fn read_database(filename: &str) -> Result<i64, Box<Error>> {
+            let file = OpenOptions::new().read(true).open(filename)?;
+            serde_json::from_reader(file).or_else(|e| {
+                Err(From::from(
+                    format!("Cannot deserialize file {}: {}", filename, e),
+                ))
+            })
+        }
+
+        fn write_database(&self) -> bool {
+            match OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.filename) {
+                Ok(file) => {
+                    if let Err(e) = serde_json::to_writer(file, &self.last_update) {
+                        error!("Cannot serialize file {}: {}", self.filename, e);
+                        return false;
+                    };
+                }
+                Err(e) => {
+                    error!("Cannot open file {}: {}", self.filename, e);
+                    return false;
+                }
+            };
+            true
+        }
+
+        /// True if the update was handled already, Telegram can send it again after a restart.
+        fn is_handled(&self, id: i64) -> bool {
+            id <= self.last_update
+        }
+
+        fn confirm(&mut self, id: i64) {
+            if id > self.last_update {
+                self.last_update = id;
+                self.write_database();
+            }
+        }
+    }
+
+    /// When the message of the update was sent, or edited for an edit.
+    fn update_date(kind: &UpdateKind) -> Option<i64> {
+        match *kind {
+            UpdateKind::Message(ref m) => Some(m.date),
+            UpdateKind::EditedMessage(ref m) => Some(m.edit_date.unwrap_or(m.date)),
+            _ => None,
+        }
+    }
+
+    /// True if a message sent at `date` is too old to be relayed at `now`, 0 disables the limit.
+    fn is_stale(date: i64, now: i64, max_age_secs: u64) -> bool {
+        max_age_secs > 0 && now - date > max_age_secs as i64
+    }
+
+    fn unix_time() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    }
+
+    /// Nick addressed at the beginning of a relayed line, as in "from: alice: sounds good" or
+    /// "[tag] from: @alice, sounds good".
+    fn addressed_nick(text: &str, from: &str) -> Option<String> {
+        let prefix = format!("{}: ", from);
+        let body = match text.find(&prefix) {
+            Some(n) => &text[n + prefix.len()..],
+            None => return None,
+        };
+        let re = Regex::new(r"^@?([^\s:,@]+)[:,]\s").unwrap();
+        re.captures(body).map(|cap| String::from(&cap[1]))
+    }
+
+    /// Formatting of Telegram entities, the ones IRC cannot render are left out.
+    fn spans(entities: &[MessageEntity]) -> Vec<Span> {
+        entities
+            .iter()
+            .filter_map(|entity| {
+                let style = match entity.kind {
+                    MessageEntityKind::Bold => SpanStyle::Bold,
+                    MessageEntityKind::Italic => SpanStyle::Italic,
+                    MessageEntityKind::Code | MessageEntityKind::Pre => SpanStyle::Code,
+                    MessageEntityKind::TextLink(ref url) => SpanStyle::Link(url.clone()),
+                    _ => return None,
+                };
+                Some(Span {
+                    offset: entity.offset as usize,
+                    length: entity.length as usize,
+                    style,
+                })
+            })
+            .collect()
+    }
+
+    /// A message waiting in the outbox for its Telegram chat.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Outgoing {
+        text: String,
+        from: String,
+        message_type: MessageType,
+        is_from_command: bool,
+    }
+
+    /// Parts of a message Telegram refused, which it is sent again without.
+    #[derive(Debug, PartialEq)]
+    enum Refused {
+        /// The message replied to was deleted.
+        ReplyTarget,
+        /// The HTML converted from mIRC formatting.
+        Entities,
+    }
+
+    fn refused(description: &str) -> Option<Refused> {
+        let description = description.to_lowercase();
+        if description.contains("reply message not found") ||
+            description.contains("replied message not found")
+        {
+            Some(Refused::ReplyTarget)
+        } else if description.contains("can't parse entities") {
+            Some(Refused::Entities)
+        } else {
+            None
+        }
+    }
+
+    fn send_message_request(
+        chat: ChatId,
+        msg: &Outgoing,
+        reply_to: Option<i64>,
+        html: bool,
+    ) -> SendMessage<'static> {
+        let mut request = if html {
+            // mIRC formatting is converted to HTML, which needs escaping anyway
+            let text = if msg.message_type == MessageType::Action {
+                format!("<i>* {}</i>", irc_to_html(&msg.text))
+            } else {
+                irc_to_html(&msg.text)
+            };
+            let mut request = SendMessage::new(chat, text);
+            request.parse_mode(ParseMode::Html);
+            request
+        } else if msg.message_type == MessageType::Action {
+            SendMessage::new(chat, format!("* {}", strip_irc_formatting(&msg.text)))
+        } else {
+            SendMessage::new(chat, strip_irc_formatting(&msg.text))
+        };
+        if let Some(id) = reply_to {
+            request.reply_to(MessageId::new(id));
+        }
+        request
+    }
+
+    /// Queues the messages for Telegram in the outbox, so that a slow or unreachable Telegram
+    /// never blocks the other transports.
+    fn main_to_telegram_loop(
+        from_main_queue: &Receiver<Message>,
+        chat_id: i64,
+        outbox: &Outbox<Outgoing>,
+    ) {
         loop {
             let current: Option<Message> = from_main_queue.recv();
             match current {
                 Some(msg) => {
-                    match core.run(api.send(SendMessage::new(chat, msg.text))) {
-                        Ok(_) => info!("Telegram message sent"),
-                        Err(e) => error!("Sending message failed for reason: {:#?}", e),
+                    // messages not addressed to a known chat go to the default one
+                    let chat = match msg.to {
+                        DestinationType::Channel(ref c) if c.parse::<i64>().is_ok() => c.clone(),
+                        _ => chat_id.to_string(),
                     };
+                    outbox.push(
+                        &chat,
+                        Outgoing {
+                            text: msg.text,
+                            from: msg.from,
+                            message_type: msg.message_type,
+                            is_from_command: msg.is_from_command,
+                        },
+                    );
                 }
                 None => {
                     info!("No message to read on internal channel");
@@ -35,94 +315,1226 @@ pub mod client {
         }
     }
 
-    fn telegram_to_main_loop(to_main_queue: &Sender<Message>, token: &str) {
+    /// Delivers the outbox, one message at a time for every chat. Failures are retried with
+    /// backoff, or after the delay asked by Telegram, and migrated groups are followed.
+    fn telegram_sender_loop(
+        outbox: &Outbox<Outgoing>,
+        token: &str,
+        api_url: &str,
+        message_ids: &Arc<Mutex<MessageIds>>,
+    ) {
+        let mut core = Core::new().unwrap();
+        let api = Api::configure(token).base_url(api_url).build(core.handle());
         loop {
-            let mut core = Core::new().unwrap();
-            let api = Api::configure(token).build(core.handle());
-            let future = api.stream().for_each(|update| {
+            let (chat_key, msg) = outbox.next();
+            let chat = match chat_key.parse::<i64>() {
+                Ok(id) => ChatId::new(id),
+                Err(_) => {
+                    error!("Invalid Telegram chat {}, dropping message", chat_key);
+                    outbox.done(&chat_key);
+                    continue;
+                }
+            };
+            // "nick: ..." lines become replies to the latest message of nick
+            let mut reply_to = addressed_nick(&msg.text, &msg.from).and_then(|nick| {
+                message_ids.lock().unwrap().get(&chat_key, &nick)
+            });
+            let mut html = true;
+            // sent again right away only without what was refused, the other errors go
+            // through the outbox retries below
+            let result = loop {
+                let result =
+                    core.run(api.send(send_message_request(chat, &msg, reply_to, html)));
+                let refusal = match result {
+                    Err(ref e) => {
+                        match *e.kind() {
+                            TelegramErrorKind::TelegramError { ref description, .. } => {
+                                refused(description)
+                            }
+                            _ => None,
+                        }
+                    }
+                    Ok(_) => None,
+                };
+                match refusal {
+                    Some(Refused::ReplyTarget) if reply_to.is_some() => {
+                        warn!("The message replied to is gone, sending without reply");
+                        reply_to = None;
+                    }
+                    Some(Refused::Entities) if html => {
+                        warn!("Telegram cannot parse the formatting, sending as plain text");
+                        html = false;
+                    }
+                    _ => break result,
+                }
+            };
+            let e = match result {
+                Ok(sent) => {
+                    info!("Telegram message sent");
+                    if !msg.is_from_command {
+                        message_ids.lock().unwrap().update(
+                            chat_key.clone(),
+                            msg.from,
+                            i64::from(sent.id),
+                        );
+                    }
+                    outbox.done(&chat_key);
+                    continue;
+                }
+                Err(e) => e,
+            };
+            match *e.kind() {
+                TelegramErrorKind::TelegramError {
+                    ref description,
+                    ref parameters,
+                } => {
+                    let (migrate_to, retry_after) = match *parameters {
+                        Some(ref p) => (p.migrate_to_chat_id, p.retry_after),
+                        None => (None, None),
+                    };
+                    if let Some(to) = migrate_to {
+                        warn!(
+                            "Telegram chat {} migrated to {}, update the configuration",
+                            chat_key,
+                            to
+                        );
+                        outbox.migrate(&chat_key, &to.to_string());
+                    } else if let Some(secs) = retry_after {
+                        warn!("Telegram flood control, retrying in {} seconds", secs);
+                        outbox.retry_after(&chat_key, Duration::from_secs(secs.max(1) as u64));
+                    } else {
+                        // the request itself is refused, sending it again will not help
+                        error!("Telegram refused message: {}", description);
+                        outbox.done(&chat_key);
+                    }
+                }
+                _ => {
+                    warn!("Sending message failed, will retry: {:#?}", e);
+                    outbox.backoff(&chat_key);
+                }
+            }
+        }
+    }
+
+    // how many message texts are remembered to show what an edit changed
+    const RECENT_TEXTS: usize = 1000;
+    // edits touching more words than this are relayed in full
+    const MAX_DIFF_WORDS: usize = 3;
+
+    /// Texts of the last messages seen, by chat and message id.
+    #[derive(Debug, Default)]
+    struct RecentTexts {
+        texts: HashMap<String, String>,
+        order: VecDeque<String>,
+    }
+
+    impl RecentTexts {
+        /// Remembers `text` for `key`, returning the text it replaces.
+        fn insert(&mut self, key: String, text: String) -> Option<String> {
+            let previous = self.texts.insert(key.clone(), text);
+            if previous.is_none() {
+                self.order.push_back(key);
+                if self.order.len() > RECENT_TEXTS {
+                    if let Some(oldest) = self.order.pop_front() {
+                        self.texts.remove(&oldest);
+                    }
+                }
+            }
+            previous
+        }
+    }
+
+    /// Word level "old → new" rendering of a small edit. None when the edit is too big to
+    /// be readable as a diff.
+    fn short_diff(old: &str, new: &str) -> Option<String> {
+        let old_words: Vec<&str> = old.split_whitespace().collect();
+        let new_words: Vec<&str> = new.split_whitespace().collect();
+        let prefix = old_words
+            .iter()
+            .zip(&new_words)
+            .take_while(|&(a, b)| a == b)
+            .count();
+        let suffix = old_words[prefix..]
+            .iter()
+            .rev()
+            .zip(new_words[prefix..].iter().rev())
+            .take_while(|&(a, b)| a == b)
+            .count();
+        let removed = &old_words[prefix..old_words.len() - suffix];
+        let added = &new_words[prefix..new_words.len() - suffix];
+        if (removed.is_empty() && added.is_empty()) || prefix + suffix == 0 ||
+            removed.len() > MAX_DIFF_WORDS || added.len() > MAX_DIFF_WORDS
+        {
+            return None;
+        }
+        Some(if removed.is_empty() {
+            format!("+{}", added.join(" "))
+        } else if added.is_empty() {
+            format!("-{}", removed.join(" "))
+        } else {
+            format!("{} → {}", removed.join(" "), added.join(" "))
+        })
+    }
+
+    // length of the excerpt of the message answered by a reply
+    const EXCERPT_CHARS: usize = 40;
+
+    fn excerpt(text: &str) -> String {
+        if text.chars().count() <= EXCERPT_CHARS {
+            return String::from(text);
+        }
+        let short: String = text.chars().take(EXCERPT_CHARS).collect();
+        format!("{}…", short.trim_right())
+    }
+
+    /// Splits a "[tag] nick: text" line relayed by the bot into the original author and text.
+    fn relayed_author(text: &str) -> Option<(&str, &str)> {
+        let text = match text.find("] ") {
+            Some(n) if text.starts_with('[') => &text[n + 2..],
+            _ => text,
+        };
+        match text.find(": ") {
+            Some(n) if n > 0 && !text[..n].contains(char::is_whitespace) => {
+                Some((&text[..n], &text[n + 2..]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Author and text of a quoted message. When the message was relayed by the bot, the
+    /// original author is named, so they get highlighted.
+    fn quoted(quote: &TelegramMessage, bot_id: Option<UserId>) -> (String, String) {
+        let text = match quote.kind {
+            MessageKind::Text { ref data, .. } => data.clone(),
+            ref kind => {
+                match media(kind) {
+                    Some(media) => media_text(&media, None),
+                    None => shared_text(kind).unwrap_or_default(),
+                }
+            }
+        };
+        let from_bot = match (quote.from.as_ref(), bot_id) {
+            (Some(user), Some(id)) => user.id == id,
+            _ => false,
+        };
+        let relayed = match relayed_author(&text) {
+            Some((nick, relayed)) if from_bot => Some((String::from(nick), String::from(relayed))),
+            _ => None,
+        };
+        relayed.unwrap_or_else(|| (sender_name(&quote.from), text))
+    }
+
+    /// "[re nick: excerpt]" context shown before replies.
+    fn reply_context(reply: &TelegramMessage, bot_id: Option<UserId>) -> String {
+        let (author, text) = quoted(reply, bot_id);
+        if text.is_empty() {
+            format!("[re {}]", author)
+        } else {
+            format!("[re {}: {}]", author, excerpt(&text))
+        }
+    }
+
+    /// Announcement of the group changes enabled in the [telegram.announce] section.
+    fn chat_event(
+        kind: &MessageKind,
+        from: &str,
+        announce: &TelegramAnnounce,
+        bot_id: Option<UserId>,
+    ) -> Option<String> {
+        match *kind {
+            MessageKind::NewChatMembers { ref data } if announce.join => {
+                let names: Vec<String> = data.iter().map(user_name).collect();
+                if names.len() == 1 && names[0] == from {
+                    Some(format!("{} joined the Telegram group", from))
+                } else {
+                    Some(format!("{} added {} to the Telegram group", from, names.join(", ")))
+                }
+            }
+            MessageKind::LeftChatMember { ref data } if announce.leave => {
+                let name = user_name(data);
+                if name == from {
+                    Some(format!("{} left the Telegram group", from))
+                } else {
+                    Some(format!("{} was removed from the Telegram group by {}", name, from))
+                }
+            }
+            MessageKind::NewChatTitle { ref data } if announce.title => {
+                Some(format!("{} changed the Telegram group title to: {}", from, data))
+            }
+            MessageKind::NewChatPhoto { .. } if announce.photo => {
+                Some(format!("{} changed the Telegram group photo", from))
+            }
+            MessageKind::PinnedMessage { ref data } if announce.pinned => {
+                let (author, text) = quoted(data, bot_id);
+                Some(format!("{} pinned a message of {}: {}", from, author, excerpt(&text)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Username if set, profile names otherwise.
+    fn user_name(user: &User) -> String {
+        match user.username {
+            // if username is provided, use it
+            Some(ref username) => username.clone(),
+            // username is not provided,use telegram profile names
+            None => {
+                // first_name always contains something
+                match user.last_name {
+                    Some(ref last_name) => format!("{} {}", user.first_name, last_name),
+                    None => user.first_name.clone(),
+                }
+            }
+        }
+    }
+
+    fn sender_name(user: &Option<User>) -> String {
+        match *user {
+            Some(ref u) => user_name(u),
+            // user is not present, should never happen
+            None => String::from("user unset"),
+        }
+    }
+
+    fn destination(chat: &Chat, from: &str) -> DestinationType {
+        match *chat {
+            Chat::Private(_) => DestinationType::User(String::from(from)),
+            Chat::Group(_) | Chat::Supergroup(_) | Chat::Channel(_) => {
+                DestinationType::Channel(chat.id().to_string())
+            }
+            Chat::Unknown(_) => DestinationType::Unknown,
+        }
+    }
+
+    /// Stores the media received from Telegram where the http server can serve them.
+    #[derive(Debug, Clone)]
+    struct MediaStore {
+        token: String,
+        api_url: String,
+        dir: String,
+        base_url: String,
+    }
+
+    impl MediaStore {
+        /// Downloads `file` under a random name and returns its public link.
+        fn download(&self, file: &TelegramFile) -> Result<String, Box<Error>> {
+            let url = match file.get_url_with_base(&self.api_url, &self.token) {
+                Some(u) => u,
+                None => return Err(From::from("file has no download path")),
+            };
+            let extension = file.file_path
+                .as_ref()
+                .and_then(|p| Path::new(p).extension())
+                .and_then(|e| e.to_str())
+                .unwrap_or("bin");
+            let name: String = rand::thread_rng().gen_ascii_chars().take(16).collect();
+            let name = format!("{}.{}", name, extension);
+
+            let mut resp = reqwest::get(&url)?;
+            if !resp.status().is_success() {
+                return Err(From::from(format!("download failed with status {}", resp.status())));
+            }
+            let mut out = fs::File::create(format!("{}/{}", self.dir, name))?;
+            io::copy(&mut resp, &mut out)?;
+            Ok(format!("{}/media/{}", self.base_url.trim_right_matches('/'), name))
+        }
+    }
+
+    /// A media message: how it is labelled on the other transports, the file to download
+    /// and an optional caption.
+    #[derive(Debug)]
+    struct Media {
+        label: &'static str,
+        file: FileRef,
+        caption: Option<String>,
+    }
+
+    fn media(kind: &MessageKind) -> Option<Media> {
+        let (label, file_id, caption) = match *kind {
+            MessageKind::Audio { ref data } => ("audio", data.file_id.clone(), data.title.clone()),
+            MessageKind::Document {
+                ref data,
+                ref caption,
+            } => (
+                "document",
+                data.file_id.clone(),
+                caption.clone().or_else(|| data.file_name.clone()),
+            ),
+            // sizes are sorted from the smallest, relay the biggest one
+            MessageKind::Photo {
+                ref data,
+                ref caption,
+            } => match data.last() {
+                Some(photo) => ("photo", photo.file_id.clone(), caption.clone()),
+                None => return None,
+            },
+            MessageKind::Sticker { ref data } => {
+                ("sticker", data.file_id.clone(), data.emoji.clone())
+            }
+            MessageKind::Video {
+                ref data,
+                ref caption,
+            } => ("video", data.file_id.clone(), caption.clone()),
+            MessageKind::Voice { ref data } => ("voice", data.file_id.clone(), None),
+            MessageKind::VideoNote { ref data } => ("video note", data.file_id.clone(), None),
+            _ => return None,
+        };
+        Some(Media {
+            label,
+            file: FileRef::from(file_id),
+            caption,
+        })
+    }
+
+    /// "[photo] http://host/media/abc.jpg (caption)", the link is missing when the
+    /// media could not be stored.
+    fn media_text(media: &Media, link: Option<String>) -> String {
+        let mut text = format!("[{}]", media.label);
+        if let Some(link) = link {
+            text = format!("{} {}", text, link);
+        }
+        if let Some(ref caption) = media.caption {
+            text = format!("{} ({})", text, caption);
+        }
+        text
+    }
+
+    fn map_link(location: &Location) -> String {
+        format!(
+            "https://www.openstreetmap.org/?mlat={lat:.5}&mlon={lon:.5}#map=17/{lat:.5}/{lon:.5}",
+            lat = location.latitude,
+            lon = location.longitude
+        )
+    }
+
+    /// Text rendering of the locations, venues and contacts shared on Telegram.
+    fn shared_text(kind: &MessageKind) -> Option<String> {
+        match *kind {
+            MessageKind::Location { ref data } => {
+                Some(format!("[location] {}", map_link(data)))
+            }
+            MessageKind::Venue { ref data } => {
+                Some(format!(
+                    "[venue] {}, {} {}",
+                    data.title,
+                    data.address,
+                    map_link(&data.location)
+                ))
+            }
+            MessageKind::Contact { ref data } => {
+                let name = match data.last_name {
+                    Some(ref last_name) => format!("{} {}", data.first_name, last_name),
+                    None => data.first_name.clone(),
+                };
+                Some(format!("[contact] {} {}", name, data.phone_number))
+            }
+            _ => None,
+        }
+    }
+
+    fn relay_media(
+        api: &Api,
+        handle: &Handle,
+        store: &Option<MediaStore>,
+        media: Media,
+        from: String,
+        to: DestinationType,
+        to_main_queue: Sender<Message>,
+    ) {
+        let store = match *store {
+            Some(ref s) => s.clone(),
+            None => {
+                to_main_queue.send(Message::new(
+                    TransportType::Telegram,
+                    media_text(&media, None),
+                    from,
+                    to,
+                    false,
+                ));
+                return;
+            }
+        };
+        let request = api.send(GetFile::new(media.file.clone()));
+        handle.spawn(request.then(move |result| {
+            match result {
+                Ok(file) => {
+                    // downloads can be slow, keep them out of the event loop
+                    thread::spawn(move || {
+                        let link = match store.download(&file) {
+                            Ok(link) => Some(link),
+                            Err(e) => {
+                                error!("Cannot download {} from Telegram: {}", media.label, e);
+                                None
+                            }
+                        };
+                        to_main_queue.send(Message::new(
+                            TransportType::Telegram,
+                            media_text(&media, link),
+                            from,
+                            to,
+                            false,
+                        ));
+                    });
+                }
+                Err(e) => {
+                    error!("Cannot get {} file from Telegram: {:#?}", media.label, e);
+                    to_main_queue.send(Message::new(
+                        TransportType::Telegram,
+                        media_text(&media, None),
+                        from,
+                        to,
+                        false,
+                    ));
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    /// The path of the webhook url, served by the embedded listener.
+    fn webhook_path(url: &str) -> String {
+        let rest = match url.find("://") {
+            Some(n) => &url[n + 3..],
+            None => url,
+        };
+        match rest.find('/') {
+            Some(n) => String::from(&rest[n..]),
+            None => String::from("/"),
+        }
+    }
+
+    /// The update posted to the webhook, or the response refusing the request.
+    fn webhook_update(request: &Request, secret: &Option<String>) -> Result<Update, Response> {
+        if request.method != "POST" {
+            return Err(Response::text(405, "method not allowed"));
+        }
+        if let Some(ref secret) = *secret {
+            if request.header("X-Telegram-Bot-Api-Secret-Token") != Some(secret.as_str()) {
+                warn!("Telegram webhook request with a wrong secret token");
+                return Err(Response::text(403, "forbidden"));
+            }
+        }
+        serde_json::from_slice(&request.body).map_err(|e| {
+            warn!("Invalid Telegram update: {}", e);
+            Response::text(400, "bad request")
+        })
+    }
+
+    fn tls_acceptor(identity: &str, password: &Option<String>) -> Result<TlsAcceptor, Box<Error>> {
+        let mut der = vec![];
+        fs::File::open(identity)?.read_to_end(&mut der)?;
+        let password = password.as_ref().map_or("", |p| p.as_str());
+        Ok(TlsAcceptor::builder(Pkcs12::from_der(&der, password)?)?.build()?)
+    }
+
+    /// Starts the listener receiving the updates posted by Telegram.
+    fn start_webhook(
+        webhook: &TelegramWebhook,
+        to_updates: UnboundedSender<Update>,
+    ) -> Result<(), Box<Error>> {
+        let mut server = HttpServer::new();
+        let secret = webhook.secret.clone();
+        let to_updates = Mutex::new(to_updates);
+        server.route(&webhook_path(&webhook.url), move |request| {
+            match webhook_update(request, &secret) {
+                Ok(update) => {
+                    if to_updates.lock().unwrap().unbounded_send(update).is_err() {
+                        return Response::text(503, "unavailable");
+                    }
+                    Response::text(200, "ok")
+                }
+                Err(response) => response,
+            }
+        });
+        match webhook.tls_identity {
+            Some(ref identity) => {
+                let acceptor = tls_acceptor(identity, &webhook.tls_identity_password)?;
+                server.start_tls(&webhook.listen, acceptor)?
+            }
+            None => server.start(&webhook.listen)?,
+        };
+        Ok(())
+    }
+
+    type Updates<'a> = Box<Stream<Item = Update, Error = Box<Error>> + 'a>;
+
+    /// The updates posted to the webhook. The receiver is kept across reconnections.
+    fn webhook_updates<'a>(receiver: &'a mut UnboundedReceiver<Update>) -> Updates<'a> {
+        Box::new(receiver.by_ref().map_err(|_| -> Box<Error> {
+            From::from("webhook listener stopped")
+        }))
+    }
+
+    /// The updates polled with getUpdates, starting after `last_update`.
+    fn polled_updates<'a>(api: &Api, last_update: i64) -> Updates<'a> {
+        let mut stream = api.stream();
+        if last_update > 0 {
+            stream.offset(last_update + 1);
+        }
+        Box::new(stream.map_err(|e| -> Box<Error> { From::from(e.to_string()) }))
+    }
+
+    fn telegram_to_main_loop(
+        to_main_queue: &Sender<Message>,
+        token: &str,
+        api_url: &str,
+        media_store: &Option<MediaStore>,
+        message_ids: &Arc<Mutex<MessageIds>>,
+        announce: &TelegramAnnounce,
+        mut webhook: Option<(TelegramWebhook, UnboundedReceiver<Update>)>,
+        offset: &mut UpdateOffset,
+        max_update_age_secs: u64,
+        link: &Link,
+    ) {
+        let mut recent_texts = RecentTexts::default();
+        link.supervise(|| {
+            let mut core = Core::new()?;
+            let handle = core.handle();
+            let api = Api::configure(token).base_url(api_url).build(core.handle());
+            // needed to recognize the replies to the messages relayed by the bot, and a check
+            // that Telegram is reachable
+            let bot_id = Some(core.run(api.send(GetMe))?.id);
+            // both modes feed the same stream of updates
+            let updates = match webhook {
+                Some((ref settings, ref mut receiver)) => {
+                    let mut request = SetWebhook::new(settings.url.as_str());
+                    if let Some(ref secret) = settings.secret {
+                        request.secret_token(secret.as_str());
+                    }
+                    core.run(api.send(request))?;
+                    info!("Receiving Telegram updates on {}", settings.url);
+                    webhook_updates(receiver)
+                }
+                None => {
+                    // getUpdates is refused while a webhook is registered
+                    core.run(api.send(DeleteWebhook))?;
+                    polled_updates(&api, offset.last_update)
+                }
+            };
+            link.up();
+            let future = updates.for_each(|update| {
+                let id = update.id;
+                if offset.is_handled(id) {
+                    debug!("Skipping Telegram update {}, already handled", id);
+                    return Ok(());
+                }
+                let stale = update_date(&update.kind).map_or(false, |date| {
+                    is_stale(date, unix_time(), max_update_age_secs)
+                });
+                if stale {
+                    debug!("Skipping Telegram update {}, too old", id);
+                    offset.confirm(id);
+                    return Ok(());
+                }
                 match update.kind {
                     UpdateKind::Message(m) => {
+                        let from = sender_name(&m.from);
+                        let to = destination(&m.chat, &from);
+                        let key = format!("{}/{}", m.chat.id(), m.id);
+                        let context = m.reply_to_message.as_ref().map(|reply| {
+                            reply_context(reply, bot_id)
+                        });
+                        if m.from.is_some() {
+                            message_ids.lock().unwrap().update(
+                                m.chat.id().to_string(),
+                                from.clone(),
+                                i64::from(m.id),
+                            );
+                        }
                         match m.kind {
                             MessageKind::Text { data, entities } => {
-                                let from: String = match m.from {
-                                    // user is present, check its fields
-                                    Some(u) => {
-                                        match u.username {
-                                            // if username is provided, use it
-                                            Some(username) => username,
-                                            // username is not provided,use telegram profile names
-                                            None => {
-                                                // first_name always contains something
-                                                match u.last_name {
-                                                    Some(last_name) => {
-                                                        format!("{} {}", u.first_name, last_name)
-                                                    }
-                                                    None => u.first_name,
-                                                }
-                                            }
-                                        }
-                                    }
-                                    // user is not present, should never happen
-                                    None => String::from("user unset"),
+                                recent_texts.insert(key, data.clone());
+                                let data = spans_to_irc(&data, &spans(&entities));
+                                // "/me waves" is an action, like on IRC
+                                let (data, message_type) = if data.starts_with("/me ") {
+                                    (String::from(&data["/me ".len()..]), MessageType::Action)
+                                } else {
+                                    (data, MessageType::Text)
                                 };
-                                let to: DestinationType = match m.chat {
-                                    Chat::Private(_) => DestinationType::User(from.clone()),
-                                    Chat::Group(g) => DestinationType::Channel(g.title),
-                                    Chat::Supergroup(s) => DestinationType::Channel(s.title),
-                                    Chat::Channel(c) => DestinationType::Channel(c.title),
-                                    Chat::Unknown(_) => DestinationType::Unknown,
+                                let data = match (context, message_type) {
+                                    (Some(context), MessageType::Text) => {
+                                        format!("{} {}", context, data)
+                                    }
+                                    _ => data,
                                 };
                                 debug!(
-                                    "Incoming Telegram message source: #cattedrale, \
+                                    "Incoming Telegram message \
                                 text: {}, src_nick: {}, to: {:?}, entities: {:?}",
                                     data,
                                     from,
                                     to,
                                     entities
                                 );
-                                to_main_queue.send(Message::new(
-                                    TransportType::Telegram,
-                                    data,
-                                    from,
-                                    to,
-                                    false,
-                                ));
+                                to_main_queue.send(
+                                    Message::new(TransportType::Telegram, data, from, to, false)
+                                        .with_type(message_type),
+                                );
                             }
-                            _ => {
-                                debug!("Telegram message type != text");
+                            ref kind => {
+                                match media(kind) {
+                                    Some(media) => {
+                                        debug!("Incoming Telegram {} from {}", media.label, from);
+                                        relay_media(
+                                            &api,
+                                            &handle,
+                                            media_store,
+                                            media,
+                                            from,
+                                            to,
+                                            to_main_queue.clone(),
+                                        );
+                                    }
+                                    None => {
+                                        if let Some(text) = shared_text(kind) {
+                                            to_main_queue.send(Message::new(
+                                                TransportType::Telegram,
+                                                text,
+                                                from,
+                                                to,
+                                                false,
+                                            ));
+                                        } else if let Some(text) =
+                                            chat_event(kind, &from, announce, bot_id)
+                                        {
+                                            to_main_queue.send(
+                                                Message::new(
+                                                    TransportType::Telegram,
+                                                    text,
+                                                    from,
+                                                    to,
+                                                    false,
+                                                ).with_type(MessageType::Event),
+                                            );
+                                        } else {
+                                            debug!("Telegram message type not relayed");
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    UpdateKind::EditedMessage(m) => {
+                        let from = sender_name(&m.from);
+                        let to = destination(&m.chat, &from);
+                        let key = format!("{}/{}", m.chat.id(), m.id);
+                        match m.kind {
+                            MessageKind::Text { data, entities } => {
+                                let diff = recent_texts
+                                    .insert(key, data.clone())
+                                    .and_then(|old| short_diff(&old, &data));
+                                let text = diff.unwrap_or_else(|| {
+                                    spans_to_irc(&data, &spans(&entities))
+                                });
+                                debug!("Incoming Telegram edit text: {}, src_nick: {}", text, from);
+                                to_main_queue.send(
+                                    Message::new(TransportType::Telegram, text, from, to, false)
+                                        .with_type(MessageType::Correction),
+                                );
                             }
+                            _ => debug!("Telegram edit of a non text message not relayed"),
                         }
                     }
                     _ => {
                         debug!("Telegram update type != message");
                     }
                 }
+                offset.confirm(id);
                 Ok(())
             });
-            match core.run(future) {
-                Ok(item) => debug!("Item from core.run: {:#?}", item),
-                Err(e) => error!("Main loop exiting for reason: {:#?}", e),
-            }
-            warn!("Restarting message receive loop");
-        }
+            core.run(future)?;
+            Ok(())
+        })
     }
 
-    pub fn new(settings: &Settings, to_main_queue: Sender<Message>) -> Sender<Message> {
-        // TODO fix this hardcoded value
-        let (to_telegram_queue, from_main_queue) = chan::sync(100);
+    fn new(settings: &Settings, to_main_queue: Sender<Message>) -> (Sender<Message>, Link) {
+        // unbounded, the messages are moved to the outbox right away
+        let (to_telegram_queue, from_main_queue) = chan::async();
 
         info!("Created telegram client");
         debug!("Running from configuration: {:?}", settings);
 
         let token = settings.telegram.token.clone();
         let token_clone = settings.telegram.token.clone();
+        let api_url = normalize_base_url(
+            settings.telegram.api_url.as_ref().map_or(
+                TELEGRAM_URL,
+                |url| url.as_str(),
+            ),
+        );
+        let api_url_clone = api_url.clone();
         let chat_id = settings.telegram.chat_id;
 
-        thread::spawn(move || telegram_to_main_loop(&to_main_queue, &token));
+        // media are relayed as links only when the http server is configured
+        let media_store = match settings.http {
+            Some(ref http) => {
+                let dir = settings.media_dir();
+                match fs::create_dir_all(&dir) {
+                    Ok(_) => Some(MediaStore {
+                        token: token.clone(),
+                        api_url: api_url.clone(),
+                        dir,
+                        base_url: http.base_url.clone(),
+                    }),
+                    Err(e) => {
+                        error!("Cannot create media directory {}: {}", dir, e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let message_ids = Arc::new(Mutex::new(MessageIds::new(&settings.commands.data_dir)));
+        let mut offset = UpdateOffset::new(&settings.commands.data_dir);
+        let max_update_age_secs = settings.telegram.max_update_age_secs.unwrap_or(
+            DEFAULT_MAX_UPDATE_AGE_SECS,
+        );
+        let message_ids_clone = message_ids.clone();
+        let announce = settings.telegram.announce.clone();
+
+        let link = Link::new(
+            TransportType::Telegram,
+            settings.channels(&TransportType::Telegram),
+            to_main_queue.clone(),
+        );
+        let link_clone = link.clone();
+
+        let webhook = match (settings.telegram.mode, settings.telegram.webhook.clone()) {
+            (TelegramMode::Polling, _) => None,
+            (TelegramMode::Webhook, Some(webhook)) => {
+                let (to_updates, updates) = mpsc::unbounded();
+                match start_webhook(&webhook, to_updates) {
+                    Ok(_) => Some((webhook, updates)),
+                    Err(e) => {
+                        error!("Cannot start the Telegram webhook, polling instead: {}", e);
+                        None
+                    }
+                }
+            }
+            (TelegramMode::Webhook, None) => {
+                error!("Telegram webhook mode without [telegram.webhook], polling instead");
+                None
+            }
+        };
+
+        thread::spawn(move || {
+            telegram_to_main_loop(
+                &to_main_queue,
+                &token,
+                &api_url,
+                &media_store,
+                &message_ids,
+                &announce,
+                webhook,
+                &mut offset,
+                max_update_age_secs,
+                &link_clone,
+            )
+        });
+        let outbox = Arc::new(Outbox::new(&settings.commands.data_dir, "telegram"));
+        let outbox_clone = outbox.clone();
+        thread::spawn(move || {
+            main_to_telegram_loop(&from_main_queue, chat_id, &outbox)
+        });
         thread::spawn(move || {
-            main_to_telegram_loop(&from_main_queue, &token_clone, chat_id)
+            telegram_sender_loop(
+                &outbox_clone,
+                &token_clone,
+                &api_url_clone,
+                &message_ids_clone,
+            )
         });
 
-        to_telegram_queue.clone()
+        (to_telegram_queue.clone(), link)
+    }
+
+    pub struct TelegramTransport {
+        settings: Settings,
+        to_telegram_queue: Option<Sender<Message>>,
+        link: Option<Link>,
+    }
+
+    impl TelegramTransport {
+        pub fn new(settings: &Settings) -> TelegramTransport {
+            TelegramTransport {
+                settings: settings.clone(),
+                to_telegram_queue: None,
+                link: None,
+            }
+        }
+    }
+
+    impl Transport for TelegramTransport {
+        fn start(&mut self, to_main_queue: Sender<Message>) {
+            let (to_telegram_queue, link) = new(&self.settings, to_main_queue);
+            self.to_telegram_queue = Some(to_telegram_queue);
+            self.link = Some(link);
+        }
+
+        fn send(&self, msg: Message) {
+            match self.to_telegram_queue {
+                Some(ref queue) => queue.send(msg),
+                None => error!("Telegram transport not started, dropping message"),
+            }
+        }
+
+        fn name(&self) -> TransportType {
+            TransportType::Telegram
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                notices: false,
+                private_messages: false,
+            }
+        }
+
+        fn state(&self) -> LinkState {
+            self.link.as_ref().map_or(LinkState::Down, |l| l.state())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        extern crate tempdir;
+
+        use self::tempdir::TempDir;
+
+        use super::{Media, media_text, shared_text, short_diff, RecentTexts};
+        use super::{excerpt, relayed_author, addressed_nick, MessageIds, chat_event};
+        use super::{spans, webhook_path, webhook_update, UpdateOffset, is_stale};
+        use super::{refused, Refused};
+        use super::telegram_bot::types::{MessageEntity, MessageEntityKind, UpdateKind};
+        use httpserver::Request;
+        use message::{Span, SpanStyle};
+        use super::telegram_bot::types::{User, UserId};
+        use settings::TelegramAnnounce;
+        use super::telegram_bot::types::{FileRef, MessageKind, Location, Venue, Contact};
+
+        #[test]
+        fn test_media_text() {
+            let mut media = Media {
+                label: "photo",
+                file: FileRef::from("abc"),
+                caption: None,
+            };
+            assert_eq!(
+                media_text(&media, Some(String::from("http://host/media/abc.jpg"))),
+                "[photo] http://host/media/abc.jpg"
+            );
+            media.caption = Some(String::from("a cat"));
+            assert_eq!(
+                media_text(&media, Some(String::from("http://host/media/abc.jpg"))),
+                "[photo] http://host/media/abc.jpg (a cat)"
+            );
+            assert_eq!(media_text(&media, None), "[photo] (a cat)");
+        }
+
+        #[test]
+        fn test_short_diff() {
+            assert_eq!(
+                short_diff("see you at teh pub", "see you at the pub"),
+                Some(String::from("teh → the"))
+            );
+            assert_eq!(
+                short_diff("see you at the pub", "see you at the old pub"),
+                Some(String::from("+old"))
+            );
+            assert_eq!(
+                short_diff("see you at the old pub", "see you at the pub"),
+                Some(String::from("-old"))
+            );
+            // whole rewrites and whitespace changes are relayed in full
+            assert_eq!(short_diff("see you at the pub", "nevermind"), None);
+            assert_eq!(
+                short_diff("see you at the pub", "see you tomorrow at some other pub"),
+                None
+            );
+            assert_eq!(short_diff("see you", "see  you"), None);
+        }
+
+        #[test]
+        fn test_addressed_nick() {
+            assert_eq!(
+                addressed_nick("bob: alice: sounds good", "bob"),
+                Some(String::from("alice"))
+            );
+            assert_eq!(
+                addressed_nick("[dev] bob: @alice, sounds good", "bob"),
+                Some(String::from("alice"))
+            );
+            assert_eq!(addressed_nick("bob: sounds good", "bob"), None);
+            assert_eq!(addressed_nick("alice: sounds good", "bob"), None);
+        }
+
+        #[test]
+        fn test_message_ids() {
+            let data_dir = TempDir::new("holysee_telegram").unwrap();
+            let data_dir = data_dir.path().to_str().unwrap();
+            let mut ids = MessageIds::new(data_dir);
+            assert_eq!(ids.get("-100123", "alice"), None);
+            ids.update(String::from("-100123"), String::from("alice"), 10);
+            ids.update(String::from("-100123"), String::from("alice"), 12);
+            assert_eq!(ids.get("-100123", "alice"), Some(12));
+            assert_eq!(ids.get("-100456", "alice"), None);
+
+            // ids survive a restart
+            let ids = MessageIds::new(data_dir);
+            assert_eq!(ids.get("-100123", "alice"), Some(12));
+        }
+
+        fn user(id: i64, username: &str) -> User {
+            User {
+                id: UserId::new(id),
+                first_name: String::from("First"),
+                last_name: None,
+                username: Some(String::from(username)),
+            }
+        }
+
+        #[test]
+        fn test_chat_event() {
+            let mut announce = TelegramAnnounce {
+                join: true,
+                leave: true,
+                title: true,
+                photo: true,
+                pinned: true,
+            };
+            let joined = MessageKind::NewChatMembers { data: vec![user(1, "alice")] };
+            assert_eq!(
+                chat_event(&joined, "alice", &announce, None),
+                Some(String::from("alice joined the Telegram group"))
+            );
+            let added = MessageKind::NewChatMembers {
+                data: vec![user(2, "bob"), user(3, "carol")],
+            };
+            assert_eq!(
+                chat_event(&added, "alice", &announce, None),
+                Some(String::from("alice added bob, carol to the Telegram group"))
+            );
+            let removed = MessageKind::LeftChatMember { data: user(2, "bob") };
+            assert_eq!(
+                chat_event(&removed, "alice", &announce, None),
+                Some(String::from("bob was removed from the Telegram group by alice"))
+            );
+            let title = MessageKind::NewChatTitle { data: String::from("holysee") };
+            assert_eq!(
+                chat_event(&title, "alice", &announce, None),
+                Some(String::from("alice changed the Telegram group title to: holysee"))
+            );
+
+            announce.join = false;
+            assert_eq!(chat_event(&joined, "alice", &announce, None), None);
+        }
+
+        #[test]
+        fn test_spans() {
+            let entities = vec![
+                MessageEntity {
+                    offset: 0,
+                    length: 4,
+                    kind: MessageEntityKind::Bold,
+                },
+                MessageEntity {
+                    offset: 5,
+                    length: 7,
+                    kind: MessageEntityKind::Mention,
+                },
+                MessageEntity {
+                    offset: 13,
+                    length: 4,
+                    kind: MessageEntityKind::TextLink(String::from("https://example.org")),
+                },
+            ];
+            assert_eq!(
+                spans(&entities),
+                vec![
+                    Span {
+                        offset: 0,
+                        length: 4,
+                        style: SpanStyle::Bold,
+                    },
+                    Span {
+                        offset: 13,
+                        length: 4,
+                        style: SpanStyle::Link(String::from("https://example.org")),
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_excerpt() {
+            assert_eq!(excerpt("short one"), "short one");
+            assert_eq!(
+                excerpt("this message is definitely longer than forty characters"),
+                "this message is definitely longer than f…"
+            );
+        }
+
+        #[test]
+        fn test_relayed_author() {
+            assert_eq!(relayed_author("nick: hello there"), Some(("nick", "hello there")));
+            assert_eq!(
+                relayed_author("[dev] nick: hello: there"),
+                Some(("nick", "hello: there"))
+            );
+            assert_eq!(relayed_author("just a message: with a colon"), None);
+            assert_eq!(relayed_author("no colon at all"), None);
+        }
+
+        #[test]
+        fn test_recent_texts() {
+            let mut recent = RecentTexts::default();
+            assert_eq!(recent.insert(String::from("1/1"), String::from("teh")), None);
+            assert_eq!(
+                recent.insert(String::from("1/1"), String::from("the")),
+                Some(String::from("teh"))
+            );
+            for n in 0..super::RECENT_TEXTS {
+                recent.insert(format!("1/{}", n + 2), String::from("filler"));
+            }
+            assert_eq!(recent.insert(String::from("1/1"), String::from("the")), None);
+        }
+
+        #[test]
+        fn test_shared_text() {
+            let location = Location {
+                latitude: 45.4642,
+                longitude: 9.19,
+            };
+            let link = "https://www.openstreetmap.org/?mlat=45.46420&mlon=9.19000#map=17/45.46420/9.19000";
+            assert_eq!(
+                shared_text(&MessageKind::Location { data: location.clone() }),
+                Some(format!("[location] {}", link))
+            );
+
+            let venue = Venue {
+                location,
+                title: String::from("Duomo"),
+                address: String::from("Piazza del Duomo, Milano"),
+                foursquare_id: None,
+            };
+            assert_eq!(
+                shared_text(&MessageKind::Venue { data: venue }),
+                Some(format!("[venue] Duomo, Piazza del Duomo, Milano {}", link))
+            );
+
+            let contact = Contact {
+                phone_number: String::from("+39 02 1234567"),
+                first_name: String::from("Mario"),
+                last_name: Some(String::from("Rossi")),
+                user_id: None,
+            };
+            assert_eq!(
+                shared_text(&MessageKind::Contact { data: contact }),
+                Some(String::from("[contact] Mario Rossi +39 02 1234567"))
+            );
+            assert_eq!(
+                shared_text(&MessageKind::Text {
+                    data: String::from("hello"),
+                    entities: vec![],
+                }),
+                None
+            );
+        }
+
+        fn webhook_request(secret: Option<&str>, body: &[u8]) -> Request {
+            let mut headers = vec![
+                (String::from("Content-Type"), String::from("application/json")),
+            ];
+            if let Some(secret) = secret {
+                headers.push((
+                    String::from("X-Telegram-Bot-Api-Secret-Token"),
+                    String::from(secret),
+                ));
+            }
+            Request {
+                method: String::from("POST"),
+                path: String::from("/telegram/hook"),
+                headers,
+                body: body.to_vec(),
+            }
+        }
+
+        #[test]
+        fn test_webhook_path() {
+            assert_eq!(webhook_path("https://bot.example.org/telegram/hook"), "/telegram/hook");
+            assert_eq!(webhook_path("https://bot.example.org"), "/");
+        }
+
+        #[test]
+        fn test_webhook_update() {
+            let fixture = include_bytes!("../fixtures/telegram_update.json");
+            let secret = Some(String::from("s3cret"));
+
+            let request = webhook_request(Some("s3cret"), fixture);
+            let update = webhook_update(&request, &secret).unwrap();
+            assert_eq!(update.id, 815270001);
+            match update.kind {
+                UpdateKind::Message(m) => {
+                    assert_eq!(
+                        m.kind,
+                        MessageKind::Text {
+                            data: String::from("hello from telegram"),
+                            entities: vec![],
+                        }
+                    );
+                }
+                _ => panic!("not a message update"),
+            }
+
+            let refused = webhook_update(&webhook_request(Some("wrong"), fixture), &secret);
+            assert_eq!(refused.unwrap_err().status, 403);
+            let refused = webhook_update(&webhook_request(None, fixture), &secret);
+            assert_eq!(refused.unwrap_err().status, 403);
+            let refused = webhook_update(&webhook_request(None, b"{}"), &None);
+            assert_eq!(refused.unwrap_err().status, 400);
+            let mut request = webhook_request(None, fixture);
+            request.method = String::from("GET");
+            assert_eq!(webhook_update(&request, &None).unwrap_err().status, 405);
+        }
+
+        #[test]
+        fn test_update_offset() {
+            let dir = TempDir::new("holysee_telegram").unwrap();
+            let data_dir = dir.path().to_str().unwrap();
+            {
+                let mut offset = UpdateOffset::new(data_dir);
+                assert!(!offset.is_handled(1));
+                offset.confirm(41);
+                offset.confirm(40);
+                assert!(offset.is_handled(41));
+                assert!(!offset.is_handled(42));
+            }
+            // a restart resumes after the last update handled
+            let offset = UpdateOffset::new(data_dir);
+            assert_eq!(offset.last_update, 41);
+        }
+
+        #[test]
+        fn test_is_stale() {
+            let now = 1_508_400_000;
+            assert!(!is_stale(now - 60, now, 3600));
+            assert!(is_stale(now - 7200, now, 3600));
+            // 0 keeps everything
+            assert!(!is_stale(now - 7200, now, 0));
+        }
+
+        #[test]
+        fn test_refused() {
+            assert_eq!(
+                refused("Bad Request: replied message not found"),
+                Some(Refused::ReplyTarget)
+            );
+            assert_eq!(
+                refused("Bad Request: can't parse entities: Unexpected end tag at byte offset 12"),
+                Some(Refused::Entities)
+            );
+            // flood control and the like are retried by the outbox instead
+            assert_eq!(refused("Too Many Requests: retry after 5"), None);
+            assert_eq!(refused("Forbidden: bot was kicked from the group chat"), None);
+        }
     }
 }
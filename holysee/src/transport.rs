@@ -0,0 +1,77 @@
+use std::sync::Arc;
+use chan::Sender;
+
+use message::{Message, TransportType};
+use supervisor::LinkState;
+
+/// Features a transport supports, so commands can adapt what they send to it.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    /// Command output can be sent as a notice instead of a regular message.
+    pub notices: bool,
+    /// Messages can be addressed to a single user instead of a channel.
+    pub private_messages: bool,
+}
+
+pub trait Transport: Send + Sync {
+    fn start(&mut self, to_main_queue: Sender<Message>);
+    fn send(&self, msg: Message);
+    fn name(&self) -> TransportType;
+    fn capabilities(&self) -> Capabilities;
+    fn state(&self) -> LinkState;
+}
+
+/// All the running transports, addressed by their `TransportType` id. Cloning the registry is
+/// cheap, so it can be handed to threads spawned by commands.
+#[derive(Clone)]
+pub struct TransportRegistry {
+    transports: Vec<Arc<Transport>>,
+    to_main_queue: Sender<Message>,
+}
+
+impl TransportRegistry {
+    pub fn new(to_main_queue: Sender<Message>) -> TransportRegistry {
+        TransportRegistry {
+            transports: vec![],
+            to_main_queue,
+        }
+    }
+
+    pub fn register(&mut self, mut transport: Box<Transport>) {
+        if self.get(&transport.name()).is_some() {
+            warn!(
+                "Transport {} already registered, skipping registration",
+                transport.name().id()
+            );
+            return;
+        }
+        info!("Starting transport {}", transport.name().id());
+        transport.start(self.to_main_queue.clone());
+        self.transports.push(Arc::from(transport));
+    }
+
+    pub fn get(&self, id: &TransportType) -> Option<&Arc<Transport>> {
+        self.transports.iter().find(|t| t.name() == *id)
+    }
+
+    pub fn ids(&self) -> Vec<TransportType> {
+        self.transports.iter().map(|t| t.name()).collect()
+    }
+
+    pub fn capabilities(&self, id: &TransportType) -> Capabilities {
+        match self.get(id) {
+            Some(t) => t.capabilities(),
+            None => Capabilities::default(),
+        }
+    }
+
+    pub fn send(&self, to: &TransportType, msg: Message) {
+        match self.get(to) {
+            Some(t) => {
+                debug!("Sending message to {} chan", to.id());
+                t.send(msg);
+            }
+            None => error!("No transport {} registered, dropping message", to.id()),
+        }
+    }
+}
@@ -0,0 +1,703 @@
+pub mod client {
+    extern crate base64;
+    extern crate chan;
+    extern crate native_tls;
+    extern crate regex;
+
+    use std::{thread, time};
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+    use std::str;
+    use std::sync::{Arc, Mutex};
+    use chan::{Sender, Receiver};
+
+    use self::native_tls::TlsConnector;
+    use self::regex::Regex;
+
+    use settings::{Settings, Xmpp};
+    use message::{Message, MessageType, TransportType, DestinationType, strip_irc_formatting};
+    use supervisor::{Link, LinkState};
+    use transport::{Transport, Capabilities};
+
+    const READ_TIMEOUT_MS: u64 = 500;
+    // number of read timeouts to wait for an answer during the handshake
+    const HANDSHAKE_READ_ATTEMPTS: u32 = 60;
+    const RESOURCE: &str = "holysee";
+
+    trait XmppStream: Read + Write + Send {}
+    impl<T: Read + Write + Send> XmppStream for T {}
+
+    /// The stream is shared between the reading and the writing thread. Reads time out
+    /// every READ_TIMEOUT_MS so the writer is never locked out for long.
+    type SharedStream = Arc<Mutex<Box<XmppStream>>>;
+
+    /// The room each nickname was last seen in, private messages are sent through it.
+    type Occupants = Arc<Mutex<HashMap<String, String>>>;
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    }
+
+    fn unescape(text: &str) -> String {
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&")
+    }
+
+    /// "holysee@example.org/res" -> ("holysee", "example.org")
+    fn split_jid(jid: &str) -> (&str, &str) {
+        let bare = match jid.find('/') {
+            Some(n) => &jid[..n],
+            None => jid,
+        };
+        match bare.find('@') {
+            Some(n) => (&bare[..n], &bare[n + 1..]),
+            None => ("", bare),
+        }
+    }
+
+    /// "room@conference.example.org/alice" -> ("room@conference.example.org", "alice")
+    fn split_resource(jid: &str) -> (&str, &str) {
+        match jid.find('/') {
+            Some(n) => (&jid[..n], &jid[n + 1..]),
+            None => (jid, ""),
+        }
+    }
+
+    fn attribute(stanza: &str, name: &str) -> Option<String> {
+        let tag_end = stanza.find('>').unwrap_or_else(|| stanza.len());
+        let re = Regex::new(format!(r#"\s{}=['"]([^'"]*)['"]"#, name).as_ref()).unwrap();
+        re.captures(&stanza[..tag_end]).map(|cap| unescape(&cap[1]))
+    }
+
+    fn element_text(stanza: &str, name: &str) -> Option<String> {
+        let re = Regex::new(format!(r"(?s)<{0}(?:\s[^>]*)?>(.*?)</{0}>", name).as_ref()).unwrap();
+        re.captures(stanza).map(|cap| unescape(&cap[1]))
+    }
+
+    /// Splits the next complete top level element off the front of the buffer. The stream
+    /// header is never closed, so it is returned as soon as its opening tag is complete.
+    fn next_stanza(buffer: &mut String) -> Option<String> {
+        let start = match buffer.find('<') {
+            Some(n) => n,
+            None => {
+                // whitespace keepalives
+                buffer.clear();
+                return None;
+            }
+        };
+        buffer.drain(..start);
+        let tag_end = match buffer.find('>') {
+            Some(n) => n,
+            None => return None,
+        };
+        let end = if buffer.starts_with("<?") || buffer.starts_with("</") ||
+            buffer.starts_with("<stream:stream") ||
+            buffer[..tag_end].ends_with('/')
+        {
+            tag_end + 1
+        } else {
+            let name: String = buffer[1..tag_end]
+                .chars()
+                .take_while(|c| !c.is_whitespace() && *c != '/')
+                .collect();
+            let closing = format!("</{}>", name);
+            match buffer.find(&closing) {
+                Some(n) => n + closing.len(),
+                None => return None,
+            }
+        };
+        Some(buffer.drain(..end).collect())
+    }
+
+    fn write(stream: &SharedStream, data: &str) -> io::Result<()> {
+        let mut stream = stream.lock().unwrap();
+        stream.write_all(data.as_bytes())?;
+        stream.flush()
+    }
+
+    struct Connection {
+        stream: SharedStream,
+        pending: Vec<u8>,
+        buffer: String,
+    }
+
+    impl Connection {
+        fn new(stream: Box<XmppStream>) -> Connection {
+            Connection {
+                stream: Arc::new(Mutex::new(stream)),
+                pending: vec![],
+                buffer: String::new(),
+            }
+        }
+
+        fn write(&self, data: &str) -> io::Result<()> {
+            write(&self.stream, data)
+        }
+
+        /// Returns the next stanza, or None if nothing complete arrived before the read timeout.
+        fn read_stanza(&mut self) -> Result<Option<String>, Box<Error>> {
+            if let Some(stanza) = next_stanza(&mut self.buffer) {
+                return Ok(Some(stanza));
+            }
+            let mut buf = [0; 4096];
+            let read = self.stream.lock().unwrap().read(&mut buf);
+            match read {
+                Ok(0) => return Err(From::from("connection closed by server")),
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                                  e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(From::from(e)),
+            }
+            // keep incomplete utf-8 sequences for the next read
+            let valid = match str::from_utf8(&self.pending) {
+                Ok(s) => s.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            let pending: Vec<u8> = self.pending.drain(..valid).collect();
+            self.buffer.push_str(&String::from_utf8(pending).unwrap());
+            Ok(next_stanza(&mut self.buffer))
+        }
+
+        /// Reads stanzas until one of the given elements arrives.
+        fn expect(&mut self, names: &[&str]) -> Result<String, Box<Error>> {
+            let mut attempts = 0;
+            while attempts < HANDSHAKE_READ_ATTEMPTS {
+                match self.read_stanza()? {
+                    Some(stanza) => {
+                        debug!("XMPP handshake stanza: {}", stanza);
+                        if stanza.starts_with("</stream:stream") {
+                            return Err(From::from("stream closed by server"));
+                        }
+                        if names.iter().any(|n| stanza.starts_with(&format!("<{}", n))) {
+                            return Ok(stanza);
+                        }
+                    }
+                    None => attempts += 1,
+                }
+            }
+            Err(From::from(format!("timeout waiting for {:?}", names)))
+        }
+
+        /// Opens a new stream and returns the features advertised by the server.
+        fn open_stream(&mut self, domain: &str) -> Result<String, Box<Error>> {
+            self.write(&format!(
+                "<?xml version='1.0'?><stream:stream to='{}' version='1.0' \
+                 xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>",
+                domain
+            ))?;
+            self.expect(&["stream:stream"])?;
+            self.expect(&["stream:features"])
+        }
+    }
+
+    fn connect(xmpp: &Xmpp) -> Result<Connection, Box<Error>> {
+        let (user, domain) = split_jid(&xmpp.jid);
+        let tcp = TcpStream::connect((xmpp.host.as_str(), xmpp.port))?;
+        tcp.set_read_timeout(Some(time::Duration::from_millis(READ_TIMEOUT_MS)))?;
+        let mut conn = Connection::new(Box::new(tcp.try_clone()?));
+        let mut features = conn.open_stream(domain)?;
+
+        if xmpp.ssl {
+            if !features.contains("urn:ietf:params:xml:ns:xmpp-tls") {
+                return Err(From::from("server does not offer STARTTLS"));
+            }
+            conn.write("<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>")?;
+            conn.expect(&["proceed"])?;
+            let connector = TlsConnector::builder()?.build()?;
+            let tls = connector.connect(domain, tcp)?;
+            conn = Connection::new(Box::new(tls));
+            features = conn.open_stream(domain)?;
+        }
+
+        if !features.contains("PLAIN") {
+            return Err(From::from("server does not offer SASL PLAIN"));
+        }
+        let credentials = base64::encode(format!("\0{}\0{}", user, xmpp.password).as_bytes());
+        conn.write(&format!(
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>{}</auth>",
+            credentials
+        ))?;
+        if conn.expect(&["success", "failure"])?.starts_with("<failure") {
+            return Err(From::from(format!("authentication failed for {}", xmpp.jid)));
+        }
+
+        let features = conn.open_stream(domain)?;
+        conn.write(&format!(
+            "<iq type='set' id='bind1'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+             <resource>{}</resource></bind></iq>",
+            RESOURCE
+        ))?;
+        conn.expect(&["iq"])?;
+        if features.contains("urn:ietf:params:xml:ns:xmpp-session") {
+            conn.write(
+                "<iq type='set' id='sess1'><session xmlns='urn:ietf:params:xml:ns:xmpp-session'/></iq>",
+            )?;
+            conn.expect(&["iq"])?;
+        }
+        Ok(conn)
+    }
+
+    fn join(stream: &SharedStream, room: &str, nickname: &str) -> io::Result<()> {
+        write(
+            stream,
+            &format!(
+                "<presence to='{}/{}'><x xmlns='http://jabber.org/protocol/muc'>\
+                 <history maxstanzas='0'/></x></presence>",
+                escape(room),
+                escape(nickname)
+            ),
+        )
+    }
+
+    fn send_message(stream: &SharedStream, to: &str, kind: &str, text: &str) -> io::Result<()> {
+        write(
+            stream,
+            &format!(
+                "<message to='{}' type='{}'><body>{}</body></message>",
+                escape(to),
+                kind,
+                escape(text)
+            ),
+        )
+    }
+
+    fn stanza_to_message(stanza: &str, nickname: &str) -> Option<Message> {
+        if !stanza.starts_with("<message") {
+            return None;
+        }
+        let from = match attribute(stanza, "from") {
+            Some(f) => f,
+            None => return None,
+        };
+        let (room, nick) = split_resource(&from);
+        // history replayed by the room when joining is delayed by the room itself, servers
+        // may stamp live messages with a delay of their own
+        if let Some(n) = stanza.find("<delay") {
+            if attribute(&stanza[n..], "from").map_or(false, |delay_from| delay_from == room) {
+                return None;
+            }
+        }
+        if nick.is_empty() || nick == nickname {
+            return None;
+        }
+        let text = match element_text(stanza, "body") {
+            Some(b) => b,
+            None => return None,
+        };
+        let to = match attribute(stanza, "type") {
+            Some(ref t) if t == "groupchat" => DestinationType::Channel(String::from(room)),
+            Some(ref t) if t == "chat" => DestinationType::User(String::from(nick)),
+            t => {
+                debug!("XMPP message type {:?} not handled", t);
+                return None;
+            }
+        };
+        debug!(
+            "Incoming XMPP message room: {}, text: {}, src_nick: {}",
+            room,
+            text,
+            nick
+        );
+        Some(Message::new(
+            TransportType::XMPP,
+            text,
+            String::from(nick),
+            to,
+            false,
+        ))
+    }
+
+    /// The nickname and room of a stanza sent by an occupant of one of `rooms`.
+    fn occupant(stanza: &str, rooms: &[String]) -> Option<(String, String)> {
+        let from = match attribute(stanza, "from") {
+            Some(f) => f,
+            None => return None,
+        };
+        let (room, nick) = split_resource(&from);
+        if nick.is_empty() || !rooms.iter().any(|r| r == room) {
+            return None;
+        }
+        Some((String::from(nick), String::from(room)))
+    }
+
+    /// Address and message type of `to`. Users are reached through the room they were seen
+    /// in, the first room is used for them when unknown and for messages without destination.
+    fn destination(
+        to: &DestinationType,
+        occupants: &HashMap<String, String>,
+        default_room: &str,
+    ) -> (String, &'static str) {
+        match *to {
+            DestinationType::Channel(ref c) => (c.clone(), "groupchat"),
+            DestinationType::User(ref u) => {
+                let room = occupants.get(u).map_or(default_room, |r| r.as_str());
+                (format!("{}/{}", room, u), "chat")
+            }
+            DestinationType::Unknown => (String::from(default_room), "groupchat"),
+        }
+    }
+
+    /// Relays the incoming stanzas until the connection is lost.
+    fn xmpp_to_main_loop(
+        to_main_queue: &Sender<Message>,
+        conn: &mut Connection,
+        nickname: &str,
+        rooms: &[String],
+        occupants: &Occupants,
+    ) -> Result<(), Box<Error>> {
+        loop {
+            if let Some(stanza) = conn.read_stanza()? {
+                if let Some((nick, room)) = occupant(&stanza, rooms) {
+                    occupants.lock().unwrap().insert(nick, room);
+                }
+                match stanza_to_message(&stanza, nickname) {
+                    Some(msg) => to_main_queue.send(msg),
+                    None => debug!("XMPP stanza: {}", stanza),
+                }
+            }
+        }
+    }
+
+    fn main_to_xmpp_loop(
+        from_main_queue: &Receiver<Message>,
+        current_stream: &Arc<Mutex<Option<SharedStream>>>,
+        default_room: &str,
+        occupants: &Occupants,
+    ) {
+        loop {
+            let current: Option<Message> = from_main_queue.recv();
+            match current {
+                Some(msg) => {
+                    let (to, kind) =
+                        destination(&msg.to, &occupants.lock().unwrap(), default_room);
+                    // XEP-0245: clients show "/me" messages as actions of the sender
+                    let text = if msg.message_type == MessageType::Action {
+                        format!("/me {}", strip_irc_formatting(&msg.text))
+                    } else {
+                        strip_irc_formatting(&msg.text)
+                    };
+                    let stream = match *current_stream.lock().unwrap() {
+                        Some(ref stream) => stream.clone(),
+                        None => {
+                            error!("Could not send, server disconnected");
+                            continue;
+                        }
+                    };
+                    match send_message(&stream, &to, kind, &text) {
+                        Ok(_) => info!("XMPP message sent"),
+                        Err(e) => error!("Could not send, server disconnected: {}", e),
+                    }
+                }
+                None => {
+                    info!("No message to read on internal channel");
+                }
+            };
+        }
+    }
+
+    fn new(settings: &Settings, to_main_queue: Sender<Message>) -> (Sender<Message>, Link) {
+        // TODO: fix this hardcoded value
+        let (to_xmpp_queue, from_main_queue) = chan::sync(100);
+        let xmpp = match settings.xmpp {
+            Some(ref x) => x.clone(),
+            None => panic!("XMPP transport started without a [xmpp] section"),
+        };
+        info!("Created xmpp client for {}:{}", xmpp.host, xmpp.port);
+
+        let rooms = settings.channels(&TransportType::XMPP);
+        let link = Link::new(TransportType::XMPP, rooms.clone(), to_main_queue.clone());
+        // the stream of the current session, None while reconnecting
+        let current_stream: Arc<Mutex<Option<SharedStream>>> = Arc::new(Mutex::new(None));
+        let occupants: Occupants = Arc::new(Mutex::new(HashMap::new()));
+        let default_room = rooms.first().unwrap_or(&xmpp.room).clone();
+
+        let supervisor_link = link.clone();
+        let supervisor_stream = current_stream.clone();
+        let supervisor_xmpp = xmpp.clone();
+        let supervisor_occupants = occupants.clone();
+        thread::spawn(move || {
+            let xmpp = supervisor_xmpp;
+            supervisor_link.supervise(|| {
+                let mut conn = connect(&xmpp)?;
+                info!("Connection successfull");
+                for room in &rooms {
+                    join(&conn.stream, room, &xmpp.nickname)?;
+                    info!("Joined XMPP room {}", room);
+                }
+                *supervisor_stream.lock().unwrap() = Some(conn.stream.clone());
+                supervisor_link.up();
+                let result = xmpp_to_main_loop(
+                    &to_main_queue,
+                    &mut conn,
+                    &xmpp.nickname,
+                    &rooms,
+                    &supervisor_occupants,
+                );
+                *supervisor_stream.lock().unwrap() = None;
+                result
+            })
+        });
+        thread::spawn(move || {
+            main_to_xmpp_loop(&from_main_queue, &current_stream, &default_room, &occupants)
+        });
+
+        (to_xmpp_queue.clone(), link)
+    }
+
+    pub struct XmppTransport {
+        settings: Settings,
+        to_xmpp_queue: Option<Sender<Message>>,
+        link: Option<Link>,
+    }
+
+    impl XmppTransport {
+        pub fn new(settings: &Settings) -> XmppTransport {
+            XmppTransport {
+                settings: settings.clone(),
+                to_xmpp_queue: None,
+                link: None,
+            }
+        }
+    }
+
+    impl Transport for XmppTransport {
+        fn start(&mut self, to_main_queue: Sender<Message>) {
+            let (to_xmpp_queue, link) = new(&self.settings, to_main_queue);
+            self.to_xmpp_queue = Some(to_xmpp_queue);
+            self.link = Some(link);
+        }
+
+        fn send(&self, msg: Message) {
+            match self.to_xmpp_queue {
+                Some(ref queue) => queue.send(msg),
+                None => error!("XMPP transport not started, dropping message"),
+            }
+        }
+
+        fn name(&self) -> TransportType {
+            TransportType::XMPP
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                notices: false,
+                private_messages: true,
+            }
+        }
+
+        fn state(&self) -> LinkState {
+            self.link.as_ref().map_or(LinkState::Down, |l| l.state())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+        use std::time::Duration;
+
+        use std::collections::HashMap;
+
+        use super::{connect, destination, join, next_stanza, occupant, stanza_to_message};
+        use message::{TransportType, DestinationType};
+        use settings::Xmpp;
+
+        const STREAM_HEADER: &str = "<?xml version='1.0'?><stream:stream from='localhost' \
+            id='mock' version='1.0' xmlns='jabber:client' \
+            xmlns:stream='http://etherx.jabber.org/streams'>";
+
+        /// Local stand-in XMPP server: waits for each marker from the client, then answers
+        /// with the matching canned reply.
+        fn mock_server(script: Vec<(&'static str, String)>) -> u16 {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = String::new();
+                for (marker, reply) in script {
+                    while !received.contains(marker) {
+                        let mut buf = [0; 4096];
+                        let n = stream.read(&mut buf).unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        received.push_str(&String::from_utf8_lossy(&buf[..n]));
+                    }
+                    let consumed = received.find(marker).unwrap() + marker.len();
+                    received.drain(..consumed);
+                    stream.write_all(reply.as_bytes()).unwrap();
+                }
+                thread::sleep(Duration::from_secs(5));
+            });
+            port
+        }
+
+        fn settings(port: u16) -> Xmpp {
+            Xmpp {
+                jid: String::from("holysee@localhost"),
+                password: String::from("password"),
+                host: String::from("127.0.0.1"),
+                port,
+                room: String::from("room@conference.localhost"),
+                nickname: String::from("holysee"),
+                ssl: false,
+                allow_receive: true,
+            }
+        }
+
+        #[test]
+        fn test_next_stanza() {
+            let mut buffer = String::from(
+                " <message from='a'><body>one</body></message><presence/><message><bo",
+            );
+            assert_eq!(
+                next_stanza(&mut buffer),
+                Some(String::from("<message from='a'><body>one</body></message>"))
+            );
+            assert_eq!(next_stanza(&mut buffer), Some(String::from("<presence/>")));
+            assert_eq!(next_stanza(&mut buffer), None);
+            assert_eq!(buffer, "<message><bo");
+        }
+
+        #[test]
+        fn test_stanza_to_message() {
+            let msg = stanza_to_message(
+                "<message from='room@conference.localhost/alice' type='groupchat'>\
+                 <body>fish &amp; chips</body></message>",
+                "holysee",
+            ).unwrap();
+            assert_eq!(msg.from_transport, TransportType::XMPP);
+            assert_eq!(msg.from, "alice");
+            assert_eq!(msg.text, "fish & chips");
+            match msg.to {
+                DestinationType::Channel(ref c) => assert_eq!(c, "room@conference.localhost"),
+                _ => panic!("message not addressed to the room"),
+            }
+
+            // own messages echoed by the room
+            assert!(
+                stanza_to_message(
+                    "<message from='room@conference.localhost/holysee' type='groupchat'>\
+                     <body>hello</body></message>",
+                    "holysee",
+                ).is_none()
+            );
+            // room history
+            assert!(
+                stanza_to_message(
+                    "<message from='room@conference.localhost/alice' type='groupchat'>\
+                     <body>old</body><delay xmlns='urn:xmpp:delay' \
+                     from='room@conference.localhost' stamp='2017-01-01T00:00:00Z'/>\
+                     </message>",
+                    "holysee",
+                ).is_none()
+            );
+            // delayed by the server, still live
+            assert!(
+                stanza_to_message(
+                    "<message from='room@conference.localhost/alice' type='groupchat'>\
+                     <body>new</body><delay xmlns='urn:xmpp:delay' from='localhost' \
+                     stamp='2017-01-01T00:00:00Z'/></message>",
+                    "holysee",
+                ).is_some()
+            );
+        }
+
+        #[test]
+        fn test_destination() {
+            let rooms = vec![
+                String::from("room@conference.localhost"),
+                String::from("other@conference.localhost"),
+            ];
+            let mut occupants = HashMap::new();
+            let (nick, room) = occupant(
+                "<message from='other@conference.localhost/alice' type='chat'>\
+                 <body>hi</body></message>",
+                &rooms,
+            ).unwrap();
+            occupants.insert(nick, room);
+            assert!(occupant("<message from='stranger@localhost/home'/>", &rooms).is_none());
+
+            assert_eq!(
+                destination(&DestinationType::User(String::from("alice")), &occupants, &rooms[0]),
+                (String::from("other@conference.localhost/alice"), "chat")
+            );
+            assert_eq!(
+                destination(&DestinationType::User(String::from("bob")), &occupants, &rooms[0]),
+                (String::from("room@conference.localhost/bob"), "chat")
+            );
+            assert_eq!(
+                destination(&DestinationType::Unknown, &occupants, &rooms[0]),
+                (String::from("room@conference.localhost"), "groupchat")
+            );
+        }
+
+        #[test]
+        fn test_connect_and_receive() {
+            let port = mock_server(vec![
+                (
+                    "<stream:stream",
+                    format!(
+                        "{}<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>\
+                         <mechanism>PLAIN</mechanism></mechanisms></stream:features>",
+                        STREAM_HEADER
+                    ),
+                ),
+                (
+                    "</auth>",
+                    String::from("<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>"),
+                ),
+                (
+                    "<stream:stream",
+                    format!(
+                        "{}<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>\
+                         </stream:features>",
+                        STREAM_HEADER
+                    ),
+                ),
+                (
+                    "</iq>",
+                    String::from(
+                        "<iq type='result' id='bind1'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+                         <jid>holysee@localhost/holysee</jid></bind></iq>",
+                    ),
+                ),
+                (
+                    "</presence>",
+                    String::from(
+                        "<presence from='room@conference.localhost/holysee'/>\
+                         <message from='room@conference.localhost/alice' type='groupchat'>\
+                         <body>hello from xmpp</body></message>",
+                    ),
+                ),
+            ]);
+
+            let xmpp = settings(port);
+            let mut conn = connect(&xmpp).unwrap();
+            join(&conn.stream, &xmpp.room, &xmpp.nickname).unwrap();
+
+            let mut received = None;
+            for _ in 0..20 {
+                if let Some(stanza) = conn.read_stanza().unwrap() {
+                    if let Some(msg) = stanza_to_message(&stanza, &xmpp.nickname) {
+                        received = Some(msg);
+                        break;
+                    }
+                }
+            }
+            let msg = received.unwrap();
+            assert_eq!(msg.from, "alice");
+            assert_eq!(msg.text, "hello from xmpp");
+        }
+    }
+}